@@ -0,0 +1,108 @@
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use chess_diff::sketch::SpaceSaving;
+
+// The sketch as it was before the stream-summary rewrite, kept to compare
+// against: a hit removes and reinserts its counter in a BTreeSet, and every
+// key is stored twice.
+struct BTreeSpaceSaving<T> {
+    keys: HashMap<Vec<T>, (usize, usize)>,
+    counts: BTreeSet<(usize, Vec<T>)>,
+    limit: usize,
+}
+
+impl<T: Ord + Eq + Hash + Clone> BTreeSpaceSaving<T> {
+    fn new(limit: usize) -> Self {
+        BTreeSpaceSaving {
+            keys: HashMap::new(),
+            counts: BTreeSet::new(),
+            limit,
+        }
+    }
+
+    fn insert(&mut self, key: Vec<T>) {
+        if let Some(val) = self.keys.get_mut(&key) {
+            let mut old_tuple = (val.0, key);
+            val.0 += 1;
+            self.counts.remove(&old_tuple);
+            old_tuple.0 += 1;
+            self.counts.insert(old_tuple);
+        } else if self.keys.len() < self.limit {
+            self.keys.insert(key.clone(), (1, 0));
+            self.counts.insert((1, key));
+        } else {
+            let min = self.counts.iter().next().unwrap().clone();
+            self.counts.remove(&min);
+            self.keys.remove(&min.1);
+            self.keys.insert(key.clone(), (min.0 + 1, min.0));
+            self.counts.insert((min.0 + 1, key));
+        }
+    }
+}
+
+// A skewed stream of rules over five attributes, like the rule instances of
+// the diff, from a fixed seed so every run sees the same stream.
+fn rules(len: usize) -> Vec<Vec<String>> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move || {
+        state = state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (state >> 33) as f64 / (1u64 << 31) as f64
+    };
+
+    (0..len)
+        .map(|_| {
+            (0..5)
+                .map(|attribute| {
+                    // Squaring a uniform sample makes small values far more
+                    // common than large ones.
+                    let value = (next() * next() * 64.0) as usize;
+                    if value % 3 == 0 {
+                        "*".to_string()
+                    } else {
+                        format!("{}-{}", attribute, value)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn insert(c: &mut Criterion) {
+    let stream = rules(200_000);
+    let mut group = c.benchmark_group("insert");
+
+    for limit in [700, 10_000] {
+        group.bench_with_input(BenchmarkId::new("btree", limit), &limit, |b, &limit| {
+            b.iter(|| {
+                let mut sketch = BTreeSpaceSaving::new(limit);
+                for rule in stream.iter() {
+                    sketch.insert(rule.clone());
+                }
+                sketch
+            })
+        });
+        group.bench_with_input(
+            BenchmarkId::new("stream-summary", limit),
+            &limit,
+            |b, &limit| {
+                b.iter(|| {
+                    let mut sketch = SpaceSaving::new(limit);
+                    for rule in stream.iter() {
+                        sketch.insert(rule.clone());
+                    }
+                    sketch
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, insert);
+criterion_main!(benches);
@@ -1,90 +1,139 @@
 use std::env;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
 
-use differential_dataflow::input::Input;
+use differential_dataflow::input::{Input, InputSession};
 use differential_dataflow::operators::{CountTotal, Join, Threshold};
 use differential_dataflow::Collection;
+use timely::dataflow::ProbeHandle;
+use timely::worker::Worker;
+
+use chess_diff::diff::{extend, sub_rules, DiffConfig};
+use chess_diff::metric::RuleCounts;
+use chess_diff::report::{rule_output, JsonLines, RuleReport};
+use chess_diff::shard::Shard;
+use chess_diff::stats::bonferroni;
+
+// The counts of a rule as the dataflow carries them: (first_rule_count,
+// first_total_count, second_rule_count, second_total_count).
+fn rule_counts(&(a, b, c, d): &(isize, isize, isize, isize)) -> RuleCounts {
+    RuleCounts {
+        first_rule: a as u64,
+        first_total: b as u64,
+        second_rule: c as u64,
+        second_total: d as u64,
+    }
+}
 
 fn main() {
-    let subsets = vec![
-        (0, 0, 0, 1),
-        (0, 0, 1, 0),
-        (0, 0, 1, 1),
-        (0, 1, 0, 0),
-        (0, 1, 0, 1),
-        (0, 1, 1, 0),
-        (0, 1, 1, 1),
-        (1, 0, 0, 0),
-        (1, 0, 0, 1),
-        (1, 0, 1, 0),
-        (1, 0, 1, 1),
-        (1, 1, 0, 0),
-        (1, 1, 0, 1),
-        (1, 1, 1, 0),
-    ];
-
-    timely::execute_directly(move |worker| {
+    let config = DiffConfig::from_args(env::args().skip(1)).expect("bad arguments");
+
+    let timely_args = config.timely_args.clone().into_iter();
+    timely::execute_from_args(timely_args, move |worker| {
+        let output = rule_output(config.output.as_deref());
+        let mut sink = JsonLines::new(output, config.attribute_names(), config.metric);
+        let mut probe = ProbeHandle::new();
         let mut input = worker.dataflow(|scope| {
             let (input_handle, input): (_, Collection<_, Vec<String>, isize>) =
                 scope.new_collection();
-            /*
-                        let first = input.filter(|s| s[0] == "White-Knight-G").map(|mut s| {
-                            s.remove(0);
-                            s
-                        });
-                        let second = input.filter(|s| s[0] == "White-Knight-B").map(|mut s| {
-                            s.remove(0);
-                            s
-                        });
-            */
-
-            let first = input.filter(|s| s[2] == "e5").map(|mut s| {
-                s.remove(2);
-                s
+            let (test, test_config) = (config.cohorts.test.clone(), config.clone());
+            let first = input
+                .filter(move |s| test.matches(s))
+                .map(move |s| test_config.attributes_of(&s));
+            let (control, control_config) = (config.cohorts.control.clone(), config.clone());
+            let second = input
+                .filter(move |s| control.matches(s))
+                .map(move |s| control_config.attributes_of(&s));
+
+            let first_total_count = first.map(|_| ()).count_total();
+
+            let second_total_count = second.map(|_| ()).count_total();
+
+            // Build the rule lattice one level at a time, apriori style: a rule
+            // is only extended if it has enough support in the first cohort, and
+            // only counted if every rule one attribute less specific does too.
+            // Rows are paired with the frequent rules they match at each level.
+            let mut first_row_rules = first.map(|row| {
+                let empty = vec![None; row.len()];
+                (row, empty)
             });
-            let second = input.filter(|s| s[2] == "e4").map(|mut s| {
-                s.remove(2);
-                s
+            let mut second_row_rules = second.map(|row| {
+                let empty = vec![None; row.len()];
+                (row, empty)
             });
+            let (min_support, metric, threshold, alpha) = (
+                config.min_support,
+                config.metric,
+                config.threshold,
+                config.alpha,
+            );
+            let mut frequent = None;
+            let mut first_rules: Option<Collection<_, Vec<Option<String>>, isize>> = None;
+            let mut second_rules: Option<Collection<_, Vec<Option<String>>, isize>> = None;
+
+            for order in 1..=config.max_order {
+                let mut first_candidates =
+                    first_row_rules.flat_map(|(row, rule)| extend(row, rule));
+                let second_candidates = second_row_rules.flat_map(|(row, rule)| extend(row, rule));
+
+                if let Some(previous) = frequent {
+                    let admissible = first_candidates
+                        .map(|(_, rule)| rule)
+                        .distinct()
+                        .flat_map(|rule| {
+                            sub_rules(&rule)
+                                .into_iter()
+                                .map(move |sub| (sub, rule.clone()))
+                        })
+                        .semijoin(&previous)
+                        .map(|(_, rule)| rule)
+                        .count_total()
+                        .filter(move |(_, count)| *count == order as isize)
+                        .map(|(rule, _)| rule);
 
-            let get_subsets = move |x: Vec<String>| {
-                // XXX: I don't really understand the ramifications of line 24
-                let subsets = subsets.clone();
-                subsets
-                    .into_iter()
-                    .map(move |(first, second, third, fourth)| {
-                        let mut output: Vec<Option<String>> = vec![None; 4];
-                        if first == 1 {
-                            output[0] = Some(x[0].clone());
-                        }
-                        if second == 1 {
-                            output[1] = Some(x[1].clone());
-                        }
-                        if third == 1 {
-                            output[2] = Some(x[2].clone());
-                        }
-
-                        if fourth == 1 {
-                            output[3] = Some(x[3].clone());
-                        }
-                        output
+                    first_candidates = first_candidates
+                        .map(|(row, rule)| (rule, row))
+                        .semijoin(&admissible)
+                        .map(|(rule, row)| (row, rule));
+                }
+
+                let level_frequent = first_candidates
+                    .map(|(_, rule)| rule)
+                    .count_total()
+                    .map(|x| ((), x))
+                    .join(&first_total_count)
+                    .filter(move |(_, ((_, count), total))| {
+                        *count as f64 / *total as f64 >= min_support
                     })
-            };
+                    .map(|(_, ((rule, _), _))| rule);
 
-            // XXX: Terrible
-            let get_subsets_second = get_subsets.clone();
+                first_row_rules = first_candidates
+                    .map(|(row, rule)| (rule, row))
+                    .semijoin(&level_frequent)
+                    .map(|(rule, row)| (row, rule));
+                second_row_rules = second_candidates
+                    .map(|(row, rule)| (rule, row))
+                    .semijoin(&level_frequent)
+                    .map(|(rule, row)| (row, rule));
 
-            let first_rule_counts = first.flat_map(move |x| get_subsets(x)).count_total();
+                let level_first = first_row_rules.map(|(_, rule)| rule);
+                let level_second = second_row_rules.map(|(_, rule)| rule);
+                first_rules = Some(match first_rules {
+                    Some(rules) => rules.concat(&level_first),
+                    None => level_first,
+                });
+                second_rules = Some(match second_rules {
+                    Some(rules) => rules.concat(&level_second),
+                    None => level_second,
+                });
+                frequent = Some(level_frequent);
+            }
 
-            let second_rule_counts = second
-                .flat_map(move |x| get_subsets_second(x))
+            let first_rule_counts = first_rules
+                .expect("--max-order must be at least 1")
                 .count_total();
 
-            //first_cubed_counts.inspect(|(x, time, m)| println!("x: {:?} time: {} multiplicity: {}", x, time, m));
-            let first_total_count = first.map(|_| ()).count_total();
-
-            let second_total_count = second.map(|_| ()).count_total();
+            let second_rule_counts = second_rules
+                .expect("--max-order must be at least 1")
+                .count_total();
 
             let first_counts = first_rule_counts
                 .map(|x| ((), x))
@@ -95,7 +144,7 @@ fn main() {
                 .map(|_| ())
                 .count_total()
                 .map(|(_, x)| x)
-                .inspect(|(x, _, _)| println!("[first num rules] {}", x));
+                .inspect(|(x, _, _)| eprintln!("[first num rules] {}", x));
             //first_rule_counts.inspect(|(x, time, m)| {
             //    println!("[first] x: {:?} time: {} multiplicity: {}", x, time, m)
             //});
@@ -109,11 +158,11 @@ fn main() {
                 .map(|_| ())
                 .count_total()
                 .map(|(_, x)| x)
-                .inspect(|(x, _, _)| println!("[second num rules] {}", x));
+                .inspect(|(x, _, _)| eprintln!("[second num rules] {}", x));
 
             //second_counts.inspect(|(x, time, m)| println!("[second] x: {:?} time: {} multiplicity: {}", x, time, m));
 
-            // Compute risk ratios
+            // (rule, (first_rule_count, first_total_count, second_rule_count, second_total_count))
             let common_rules = first_counts.join(&second_counts).map(
                 |(
                     rule,
@@ -122,92 +171,118 @@ fn main() {
                         (second_rule_count, second_total_count),
                     ),
                 )| {
-                    let p_rule =
-                        first_rule_count as f64 / (first_rule_count + second_rule_count) as f64;
-
-                    let total_without_rule = (first_total_count - first_rule_count
-                        + second_total_count
-                        - second_rule_count) as f64;
-                    let first_without_rule = (first_total_count - first_rule_count) as f64;
-                    let first_support = first_rule_count as f64 / first_total_count as f64;
-                    let second_support = second_rule_count as f64 / second_total_count as f64;
-
-                    if total_without_rule == 0.0 {
-                        (rule, first_support, second_support, 0.0)
-                    } else if first_without_rule == 0.0 {
-                        (rule, first_support, second_support, f64::INFINITY)
-                    } else {
-                        let p_without_rule = first_without_rule / total_without_rule;
-                        (rule, first_support, second_support, p_rule / p_without_rule)
-                    }
+                    let counts = (
+                        first_rule_count,
+                        first_total_count,
+                        second_rule_count,
+                        second_total_count,
+                    );
+                    (rule, counts)
                 },
             );
 
             let counts_not_in_second = first_counts
                 .map(|(k, _)| k)
                 .distinct()
-                .concat(&common_rules.map(|(k, _, _, _)| k).distinct().negate())
+                .concat(&common_rules.map(|(k, _)| k).distinct().negate())
                 .map(|x| ((), x))
                 .join(&second_total_count)
                 .map(|(_, (rule, total))| (rule, (0, total)));
 
             let rules_only_in_first = first_counts.join(&counts_not_in_second).map(
                 |(rule, ((first_rule_count, first_total_count), (_, second_total_count)))| {
-                    let p_rule = 1.0 as f64;
-
-                    let total_without_rule =
-                        (first_total_count - first_rule_count + second_total_count) as f64;
-                    let first_without_rule = (first_total_count - first_rule_count) as f64;
-                    let first_support = first_rule_count as f64 / first_total_count as f64;
-                    let second_support = 0.0 as f64;
-
-                    if total_without_rule == 0.0 {
-                        (rule, first_support, second_support, 0.0)
-                    } else if first_without_rule == 0.0 {
-                        (rule, first_support, second_support, f64::INFINITY)
-                    } else {
-                        let p_without_rule = first_without_rule / total_without_rule;
-                        (rule, first_support, second_support, p_rule / p_without_rule)
-                    }
+                    let counts = (first_rule_count, first_total_count, 0, second_total_count);
+                    (rule, counts)
                 },
             );
 
             let rules = common_rules.concat(&rules_only_in_first);
 
+            // Every candidate rule is a hypothesis test, so p-values are
+            // corrected for how many there are. That number changes with
+            // almost every batch, so it only decides which rules pass --alpha
+            // and isn't part of the rules reported, which would otherwise all
+            // be retracted and added again each time.
+            let candidates = rules.map(|_| ()).count_total();
+            candidates
+                .map(|(_, x)| x)
+                .inspect(|(x, _, _)| eprintln!("[candidate rules] {}", x));
+
             rules
-                .filter(|(_, support, _, risk_ratio)| *support > 0.05 && *risk_ratio > 1.2)
-                .map(|(rule, support_a, _, risk_ratio)| {
-                    let out: Vec<String> =
-                        rule.into_iter().map(|x| x.unwrap_or("*".into())).collect();
-                    (out, support_a, risk_ratio)
+                .map(|x| ((), x))
+                .join(&candidates)
+                .filter(move |(_, ((_, counts), tests))| {
+                    let counts = rule_counts(counts);
+                    counts.first_support() >= min_support
+                        && metric.compute(&counts) > threshold
+                        && alpha.map_or(true, |alpha| {
+                            bonferroni(counts.p_value(), *tests as u64) <= alpha
+                        })
                 })
-                .inspect(|((x, support, risk_ratio), _, _)| {
-                    println!("[rule]: {:?} {:.2}% {:.2}", x, *support * 100.0, risk_ratio)
-                });
+                .map(|(_, (rule, _))| rule)
+                .consolidate()
+                // Rules whose counts change are retracted and added again, at
+                // the time of the batch that changed them.
+                .inspect(move |((rule, counts), time, diff)| {
+                    let counts = rule_counts(counts);
+                    let report = RuleReport {
+                        rule: rule.clone(),
+                        counts,
+                        score: metric.compute(&counts),
+                        risk_ratio_interval: counts.risk_ratio_interval(),
+                        p_value: counts.p_value(),
+                        adjusted_p_value: None,
+                        bounds: None,
+                        games: None,
+                    };
+                    sink.write(&report, *time, *diff).expect("write rule");
+                })
+                .probe_with(&mut probe);
             input_handle
         });
 
-        input.advance_to(0);
-        let mut count = 0;
-        for arg in env::args().skip(1) {
-            let file = File::open(&arg).expect("fopen");
+        // Each input file is a batch of its own, and with --batch-size so is
+        // every that many rows read by a worker. Results are brought up to date after each batch
+        // rather than once everything has been read, so a stream of games
+        // (e.g. `-` for stdin) gives a continuously updated diff.
+        let end_batch = |worker: &mut Worker<_>, input: &mut InputSession<_, _, _>, batch| {
+            input.advance_to(batch);
+            input.flush();
+            worker.step_while(|| probe.less_than(input.time()));
+        };
 
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                let l: Vec<String> = line.unwrap().split(',').map(|s| s.to_string()).collect();
+        let mut batch: usize = 0;
+        let mut count = 0;
+        let shard = Shard {
+            index: worker.index(),
+            peers: worker.peers(),
+        };
+        for (file, arg) in config.files.iter().enumerate() {
+            let mut batch_rows = 0;
+            let rows = shard.event_rows(arg, file, &config.header_names);
+            for row in rows.expect("fopen") {
+                let (_, l) = row.unwrap();
 
-                if l.len() != 5 {
-                    println!("{:?}", l);
+                if l.len() < 5 {
+                    eprintln!("{:?}", l);
                 }
                 input.insert(l);
                 count = count + 1;
+                batch_rows += 1;
 
                 if count % 10000 == 0 {
-                    //input.advance_to(count);
-                    println!("[input-count]: {}", count);
+                    eprintln!("[input-count] worker {}: {}", shard.index, count);
+                }
+
+                if config.batch_size == Some(batch_rows) {
+                    batch += 1;
+                    batch_rows = 0;
+                    end_batch(worker, &mut input, batch);
                 }
             }
-            input.insert(vec!["dummy".to_string(); 5]);
+            batch += 1;
+            end_batch(worker, &mut input, batch);
         }
     })
+    .expect("failed to start timely");
 }
@@ -1,280 +1,229 @@
-use std::collections::{BTreeSet, HashMap};
 use std::env;
-use std::fs::File;
-use std::hash::Hash;
-use std::io::{BufRead, BufReader};
+use std::fs;
 
 use differential_dataflow::input::Input;
-use differential_dataflow::operators::{CountTotal, Join, Threshold};
+use differential_dataflow::operators::{CountTotal, Join, Reduce};
 use differential_dataflow::Collection;
 
-pub struct SpaceSaving<T> {
-    keys: HashMap<Vec<T>, (usize, usize)>,
-    counts: BTreeSet<(usize, Vec<T>)>,
-    limit: usize,
-    size: usize,
-}
-
-impl<T> SpaceSaving<T>
-where
-    T: Ord + Eq + Hash + Clone,
-{
-    fn new(limit: usize) -> Self {
-        SpaceSaving {
-            keys: HashMap::new(),
-            counts: BTreeSet::new(),
-            limit,
-            size: 0,
-        }
-    }
-
-    fn insert(&mut self, key: Vec<T>) {
-        if let Some(val) = self.keys.get_mut(&key) {
-            let old_count = val.0;
-            val.0 += 1;
-
-            let mut old_tuple = (old_count, key);
-            let cleanup = self.counts.remove(&old_tuple);
-            assert!(cleanup == true);
-
-            old_tuple.0 += 1;
-            self.counts.insert(old_tuple);
-        } else {
-            if self.size < self.limit {
-                // Easy case, we have not yet filled up our quota of counters
-                self.size += 1;
-
-                self.keys.insert(key.clone(), (1, 0));
-                self.counts.insert((1, key));
-            } else {
-                // Ok now we have to evict one of our neighbors
-                assert!(self.size == self.limit);
-
-                // First, lets find the neighbor with the minimum count
-                let min = self.counts.iter().next().unwrap().clone();
-                let removal = self.counts.remove(&min);
-                assert!(removal == true);
-                self.keys.remove(&min.1).unwrap();
-
-                // Now let's insert the new key with a nonzero error term
-                let count = min.0 + 1;
-                let error = min.0;
-                self.keys.insert(key.clone(), (count, error));
-                self.counts.insert((count, key));
+use chess_diff::diff::{rule, DiffConfig};
+use chess_diff::metric::{CountBounds, RuleCounts};
+use chess_diff::report::{rule_output, JsonLines, RuleReport};
+use chess_diff::shard::Shard;
+use chess_diff::sketch::CohortSketches;
+use chess_diff::stats::bonferroni;
+
+// Every worker's sketches are merged into one.
+fn merge_sketches(_key: &(), input: &[(&String, isize)], output: &mut Vec<(String, isize)>) {
+    let mut merged: Option<CohortSketches> = None;
+    for (text, count) in input {
+        let sketches = parse_sketches(text);
+        for _ in 0..*count {
+            match merged.as_mut() {
+                Some(merged) => merged.merge(&sketches),
+                None => merged = Some(sketches.clone()),
             }
         }
     }
 
-    fn get_counts(&self) -> Vec<(Vec<T>, (usize, usize))> {
-        self.keys
-            .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect()
+    if let Some(merged) = merged {
+        let text = serde_json::to_string(&merged).expect("serialize sketches");
+        output.push((text, 1));
     }
 }
 
-fn main() {
-    timely::execute_directly(move |worker| {
-        let (mut first_rule_counts, mut second_rule_counts) = worker.dataflow(|scope| {
-            // (Rule, (rule_count, total_count))
-            let (first_handle, first_counts): (
-                _,
-                Collection<_, (Vec<String>, (usize, usize)), isize>,
-            ) = scope.new_collection();
-            let (second_handle, second_counts): (
-                _,
-                Collection<_, (Vec<String>, (usize, usize)), isize>,
-            ) = scope.new_collection();
-
-            // Compute risk ratios
-            let common_rules = first_counts.join(&second_counts).map(
-                |(
-                    rule,
-                    (
-                        (first_rule_count, first_total_count),
-                        (second_rule_count, second_total_count),
-                    ),
-                )| {
-                    let p_rule =
-                        first_rule_count as f64 / (first_rule_count + second_rule_count) as f64;
-
-                    let total_without_rule = (first_total_count - first_rule_count
-                        + second_total_count
-                        - second_rule_count) as f64;
-                    let first_without_rule = (first_total_count - first_rule_count) as f64;
-                    let first_support = first_rule_count as f64 / first_total_count as f64;
-                    let second_support = second_rule_count as f64 / second_total_count as f64;
-
-                    if total_without_rule == 0.0 {
-                        (rule, first_support, second_support, 0.0)
-                    } else if first_without_rule == 0.0 {
-                        (rule, first_support, second_support, f64::INFINITY)
-                    } else {
-                        let p_without_rule = first_without_rule / total_without_rule;
-                        (rule, first_support, second_support, p_rule / p_without_rule)
-                    }
-                },
-            );
-
-            let second_total_count = second_counts.map(|(_, (_, total))| ((), total)).distinct();
-            let counts_not_in_second = first_counts
-                .map(|(k, _)| k)
-                .distinct()
-                .concat(&common_rules.map(|(k, _, _, _)| k).distinct().negate())
-                .map(|x| ((), x))
-                .join(&second_total_count)
-                .map(|(_, (rule, total))| (rule, (0, total)));
-
-            let rules_only_in_first = first_counts.join(&counts_not_in_second).map(
-                |(rule, ((first_rule_count, first_total_count), (_, second_total_count)))| {
-                    let p_rule = 1.0 as f64;
-
-                    let total_without_rule =
-                        (first_total_count - first_rule_count + second_total_count) as f64;
-                    let first_without_rule = (first_total_count - first_rule_count) as f64;
-                    let first_support = first_rule_count as f64 / first_total_count as f64;
-                    let second_support = 0.0 as f64;
+fn parse_sketches(text: &str) -> CohortSketches {
+    serde_json::from_str(text).expect("bad sketch")
+}
 
-                    if total_without_rule == 0.0 {
-                        (rule, first_support, second_support, 0.0)
-                    } else if first_without_rule == 0.0 {
-                        (rule, first_support, second_support, f64::INFINITY)
-                    } else {
-                        let p_without_rule = first_without_rule / total_without_rule;
-                        (rule, first_support, second_support, p_rule / p_without_rule)
+fn main() {
+    let config = DiffConfig::from_args(env::args().skip(1)).expect("bad arguments");
+    let timely_args = config.timely_args.clone().into_iter();
+    timely::execute_from_args(timely_args, move |worker| {
+        let output = rule_output(config.output.as_deref());
+        let mut sink = JsonLines::new(output, config.attribute_names(), config.metric);
+        let (min_support, metric, threshold, alpha) = (
+            config.min_support,
+            config.metric,
+            config.threshold,
+            config.alpha,
+        );
+        let save_sketch = config.save_sketch.clone();
+        let mut input = worker.dataflow(|scope| {
+            // Each worker sketches the rows it reads, and the sketches are
+            // sent, serialized, to a single worker to be merged.
+            let (sketch_handle, sketches): (_, Collection<_, String, isize>) =
+                scope.new_collection();
+            let merged = sketches
+                .map(|sketch| ((), sketch))
+                .reduce(merge_sketches)
+                .map(|(_, sketch)| sketch);
+
+            if let Some(path) = save_sketch {
+                merged.inspect(move |(sketch, _, diff)| {
+                    if *diff > 0 {
+                        fs::write(&path, sketch).expect("write sketch");
                     }
-                },
-            );
+                });
+            }
 
-            let rules = common_rules.concat(&rules_only_in_first);
+            // A sketch's count for a rule overestimates it by at most the
+            // count's error, so rules carry both bounds. Rules the control
+            // sketch lost track of are bounded by its Count-Min estimate and
+            // its smallest count.
+            // (rule, ((first_lower, first_upper), first_total,
+            //         (second_lower, second_upper), second_total, games))
+            let rules = merged.flat_map(|sketch| {
+                parse_sketches(&sketch).rule_bounds().into_iter().map(
+                    |(rule, CountBounds { lower, upper }, games)| {
+                        let counts = (
+                            (lower.first_rule, upper.first_rule),
+                            upper.first_total,
+                            (lower.second_rule, upper.second_rule),
+                            upper.second_total,
+                            games,
+                        );
+                        (rule, counts)
+                    },
+                )
+            });
+
+            // Every candidate rule is a hypothesis test, so p-values are
+            // corrected for how many there are.
+            let candidates = rules.map(|_| ()).count_total();
 
             rules
-                .filter(|(_, support, _, risk_ratio)| *support > 0.05 && *risk_ratio > 1.2)
-                .map(|(rule, support_a, _, risk_ratio)| {
-                    let out: Vec<String> = rule.into_iter().collect();
-                    (out, support_a, risk_ratio)
+                .map(|x| ((), x))
+                .join(&candidates)
+                .map(
+                    move |(_, ((rule, ((a_low, a), b, (c_low, c), d, games)), tests))| {
+                        let upper = RuleCounts {
+                            first_rule: a,
+                            first_total: b,
+                            second_rule: c,
+                            second_total: d,
+                        };
+                        let bounds = CountBounds {
+                            lower: RuleCounts {
+                                first_rule: a_low,
+                                second_rule: c_low,
+                                ..upper
+                            },
+                            upper,
+                        };
+                        // Rules are scored and reported on their least
+                        // favourable counts: the fewest test cohort rows the
+                        // sketch allows, and the most control rows. For a rule
+                        // the control sketch lost track of, that's its Count-Min
+                        // estimate or the sketch's smallest count, so its ratio
+                        // errs low rather than being inflated by a count of 0.
+                        // Every metric is lowest there, so each rule reported
+                        // would be reported by the exact diff too.
+                        let counts = bounds.worst();
+                        let p_value = counts.p_value();
+                        let adjusted = bonferroni(p_value, tests as u64);
+                        let interval = counts.risk_ratio_interval();
+                        (
+                            rule,
+                            counts,
+                            metric.compute(&counts),
+                            interval,
+                            p_value,
+                            adjusted,
+                            bounds,
+                            games,
+                        )
+                    },
+                )
+                .filter(move |(_, counts, score, _, _, adjusted, _, _)| {
+                    counts.first_support() >= min_support
+                        && *score > threshold
+                        && alpha.map_or(true, |alpha| *adjusted <= alpha)
                 })
-                .inspect(|((x, support, risk_ratio), _, _)| {
-                    println!("[rule]: {:?} {:.2}% {:.2}", x, *support * 100.0, risk_ratio)
-                });
-            (first_handle, second_handle)
+                .inspect(
+                    move |(
+                        (rule, counts, score, interval, p_value, adjusted, bounds, games),
+                        time,
+                        diff,
+                    )| {
+                        let report = RuleReport {
+                            rule: rule
+                                .iter()
+                                .map(|a| if a == "*" { None } else { Some(a.clone()) })
+                                .collect(),
+                            counts: *counts,
+                            score: *score,
+                            risk_ratio_interval: *interval,
+                            p_value: *p_value,
+                            adjusted_p_value: Some(*adjusted),
+                            bounds: Some(*bounds),
+                            games: *games,
+                        };
+                        sink.write(&report, *time, *diff).expect("write rule");
+                    },
+                );
+            sketch_handle
         });
 
-        let subsets = vec![
-            (0, 0, 0, 1),
-            (0, 0, 1, 0),
-            (0, 0, 1, 1),
-            (0, 1, 0, 0),
-            (0, 1, 0, 1),
-            (0, 1, 1, 0),
-            (0, 1, 1, 1),
-            (1, 0, 0, 0),
-            (1, 0, 0, 1),
-            (1, 0, 1, 0),
-            (1, 0, 1, 1),
-            (1, 1, 0, 0),
-            (1, 1, 0, 1),
-            (1, 1, 1, 0),
-        ];
-
-        let mut first = Vec::new();
-        let mut second = Vec::new();
-        let mut first_count = 0;
-        let mut second_count = 0;
+        let combinations = config.combinations();
+        let get_subsets = |x: Vec<String>| {
+            combinations.iter().map(move |combination| {
+                rule(&x, combination)
+                    .into_iter()
+                    .map(|a| a.unwrap_or_else(|| "*".to_string()))
+                    .collect::<Vec<_>>()
+            })
+        };
 
-        for arg in env::args().skip(1) {
-            let file = File::open(&arg).expect("fopen");
+        // One sketch per file, merged into the worker's own as each file ends.
+        let counts_limit = 700;
+        let mut sketches = CohortSketches::new(counts_limit);
 
-            let reader = BufReader::new(file);
+        let shard = Shard {
+            index: worker.index(),
+            peers: worker.peers(),
+        };
+        for (file, arg) in config.files.iter().enumerate() {
             let mut count = 0;
-            for line in reader.lines() {
-                let mut l: Vec<String> = line.unwrap().split(',').map(|s| s.to_string()).collect();
+            let mut file_sketches = CohortSketches::new(counts_limit);
+            let rows = shard.event_rows(arg, file, &config.header_names);
+            for row in rows.expect("fopen") {
+                let (game, l) = row.unwrap();
 
-                if l.len() != 5 {
-                    println!("{:?}", l);
+                if l.len() < 5 {
+                    eprintln!("{:?}", l);
                 }
                 count = count + 1;
 
                 if count % 10000 == 0 {
-                    println!("[input-count]: {}", count);
+                    eprintln!("[input-count] worker {}: {}", shard.index, count);
                 }
 
-                if l[2] == "e5" {
-                    l.remove(2);
-                    first.push(l);
-                    first_count += 1;
-                } else if l[2] == "e4" {
-                    l.remove(2);
-                    second.push(l);
-                    second_count += 1;
-                }
-            }
-        }
-        let get_subsets = move |x: Vec<String>| {
-            // XXX: I don't really understand the ramifications of line 24
-            let subsets = subsets.clone();
-            subsets
-                .into_iter()
-                .map(move |(first, second, third, fourth)| {
-                    let mut output: Vec<String> = vec!["*".to_string(); 4];
-                    if first == 1 {
-                        output[0] = x[0].clone();
-                    }
-                    if second == 1 {
-                        output[1] = x[1].clone();
+                if config.cohorts.test.matches(&l) {
+                    for subset in get_subsets(config.attributes_of(&l)) {
+                        file_sketches.insert_first(subset, game);
                     }
-                    if third == 1 {
-                        output[2] = x[2].clone();
-                    }
-
-                    if fourth == 1 {
-                        output[3] = x[3].clone();
+                    file_sketches.first_total += 1;
+                }
+                // The cohorts are independent, as in the exact diff, so a row
+                // matching both counts in both.
+                if config.cohorts.control.matches(&l) {
+                    for subset in get_subsets(config.attributes_of(&l)) {
+                        file_sketches.insert_second(subset);
                     }
-                    output
-                })
-        };
-
-        let counts_limit = 700;
-        let mut counts_first = SpaceSaving::new(counts_limit);
-        let mut counts_second = SpaceSaving::new(counts_limit);
-
-        for f in first.into_iter() {
-            for subset in get_subsets(f) {
-                counts_first.insert(subset);
-            }
-        }
-
-        for s in second.into_iter() {
-            for subset in get_subsets(s) {
-                counts_second.insert(subset);
+                    file_sketches.second_total += 1;
+                }
             }
+            sketches.merge(&file_sketches);
         }
 
-        let first = counts_first.get_counts();
-        let second = counts_second.get_counts();
-
-        //println!("{:?}", first);
+        input.insert(serde_json::to_string(&sketches).expect("serialize sketches"));
 
-        let first_data: Vec<_> = first
-            .into_iter()
-            .map(|(rule, (rule_count, _))| (rule, (rule_count, first_count)))
-            .collect();
-        let second_data: Vec<_> = second
-            .into_iter()
-            .map(|(rule, (rule_count, _))| (rule, (rule_count, second_count)))
-            .collect();
-
-        first_rule_counts.advance_to(0);
-        second_rule_counts.advance_to(0);
-
-        for d in first_data.into_iter() {
-            first_rule_counts.insert(d);
-        }
-
-        for d in second_data.into_iter() {
-            second_rule_counts.insert(d);
+        // Sketches stored by earlier runs are merged in as they are.
+        if worker.index() == 0 {
+            for path in config.sketches.iter() {
+                input.insert(fs::read_to_string(path).expect("read sketch"));
+            }
         }
-
-        //second_rule_counts.insert((vec!["dummy".to_string(); 5], (0, 0)));
     })
+    .expect("failed to start timely");
 }
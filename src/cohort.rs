@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::fmt;
+
+// Problems with a cohort definition given on the command line or in a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CohortError {
+    UnknownColumn(String),
+    Syntax(String),
+    MissingCohort(&'static str),
+}
+
+impl fmt::Display for CohortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CohortError::UnknownColumn(column) => write!(f, "unknown column: {}", column),
+            CohortError::Syntax(text) => write!(f, "can't parse cohort definition: {}", text),
+            CohortError::MissingCohort(name) => write!(f, "no {} cohort defined", name),
+        }
+    }
+}
+
+impl Error for CohortError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Condition {
+    Equals(usize, String),
+    NotEquals(usize, String),
+}
+
+// A conjunction of `column=value` and `column!=value` conditions over a row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Predicate {
+    conditions: Vec<Condition>,
+}
+
+impl Predicate {
+    // Parse e.g. "event=captured AND square=e5", resolving column names
+    // against `columns`.
+    pub fn parse(text: &str, columns: &[String]) -> Result<Predicate, CohortError> {
+        let column = |name: &str| {
+            columns
+                .iter()
+                .position(|c| c == name)
+                .ok_or_else(|| CohortError::UnknownColumn(name.to_string()))
+        };
+
+        let mut conditions = Vec::new();
+        for term in text.split(" AND ").map(str::trim) {
+            let condition = if let Some(i) = term.find("!=") {
+                Condition::NotEquals(column(term[..i].trim())?, term[i + 2..].trim().to_string())
+            } else if let Some(i) = term.find('=') {
+                Condition::Equals(column(term[..i].trim())?, term[i + 1..].trim().to_string())
+            } else {
+                return Err(CohortError::Syntax(term.to_string()));
+            };
+            conditions.push(condition);
+        }
+
+        Ok(Predicate { conditions })
+    }
+
+    pub fn matches(&self, row: &[String]) -> bool {
+        self.conditions.iter().all(|condition| match condition {
+            Condition::Equals(i, value) => row.get(*i) == Some(value),
+            Condition::NotEquals(i, value) => row.get(*i) != Some(value),
+        })
+    }
+
+    // Indices of the columns this predicate looks at.
+    pub fn columns(&self) -> impl Iterator<Item = usize> + '_ {
+        self.conditions.iter().map(|condition| match condition {
+            Condition::Equals(i, _) | Condition::NotEquals(i, _) => *i,
+        })
+    }
+}
+
+// The two groups of rows being compared: rules that are more common in `test`
+// than in `control` are reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cohorts {
+    pub test: Predicate,
+    pub control: Predicate,
+}
+
+impl Cohorts {
+    // Parse e.g.
+    // "test: event=captured AND square=e5; control: event=captured AND square=e4".
+    // Definitions may also be separated by newlines, as in a config file.
+    pub fn parse(text: &str, columns: &[String]) -> Result<Cohorts, CohortError> {
+        let mut test = None;
+        let mut control = None;
+
+        for definition in text.split([';', '\n']).map(str::trim) {
+            if definition.is_empty() || definition.starts_with('#') {
+                continue;
+            }
+
+            let i = definition
+                .find(':')
+                .ok_or_else(|| CohortError::Syntax(definition.to_string()))?;
+            let predicate = Predicate::parse(&definition[i + 1..], columns)?;
+            match definition[..i].trim() {
+                "test" => test = Some(predicate),
+                "control" => control = Some(predicate),
+                _ => return Err(CohortError::Syntax(definition.to_string())),
+            }
+        }
+
+        Ok(Cohorts {
+            test: test.ok_or(CohortError::MissingCohort("test"))?,
+            control: control.ok_or(CohortError::MissingCohort("control"))?,
+        })
+    }
+
+    // Indices of the columns either cohort looks at. These define the cohorts,
+    // so they make no sense as rule attributes.
+    pub fn columns(&self) -> Vec<usize> {
+        let mut columns: Vec<_> = self.test.columns().chain(self.control.columns()).collect();
+        columns.sort_unstable();
+        columns.dedup();
+        columns
+    }
+}
@@ -0,0 +1,287 @@
+use std::error::Error;
+use std::fs;
+
+use crate::cohort::Cohorts;
+use crate::metric::Metric;
+use crate::COLUMNS;
+
+// Used when no cohorts are given: captures on e5 against captures on e4.
+const DEFAULT_COHORTS: &str = "test: square=e5; control: square=e4";
+
+// Columns used as rule attributes when none are given, apart from any the
+// cohorts are defined by.
+const DEFAULT_ATTRIBUTES: &[&str] = &["piece", "event", "square", "move", "capturer"];
+
+// Timely's options for the number of workers per process, the number of
+// processes, this process's index and the file of process addresses.
+const TIMELY_OPTIONS: &[&str] = &["-w", "-n", "-p", "-h"];
+
+// Command line configuration shared by the diff examples.
+#[derive(Debug, Clone)]
+pub struct DiffConfig {
+    pub files: Vec<String>,
+    pub header_names: Vec<String>,
+    // Names of every column in an input row
+    pub columns: Vec<String>,
+    pub cohorts: Cohorts,
+    // Indices of the columns rules are built from
+    pub attributes: Vec<usize>,
+    // Largest number of attributes a rule may specify
+    pub max_order: usize,
+    // Smallest fraction of the test cohort a rule must match to be reported
+    pub min_support: f64,
+    // How rules are scored, and the score a rule needs to be reported, which
+    // defaults to the metric's own
+    pub metric: Metric,
+    pub threshold: f64,
+    // Largest Bonferroni adjusted p-value a rule may have to be reported
+    pub alpha: Option<f64>,
+    // Number of rows after which results are brought up to date, besides at
+    // the end of every file
+    pub batch_size: Option<usize>,
+    // File rules are written to as JSON Lines, rather than stdout
+    pub output: Option<String>,
+    // Where to store the merged rule sketches, and stored sketches to merge in
+    pub save_sketch: Option<String>,
+    pub sketches: Vec<String>,
+    // Options for timely itself, like `-w 16` for the number of workers
+    pub timely_args: Vec<String>,
+}
+
+impl DiffConfig {
+    // Understands `--headers=White,Black`, naming the PGN header columns that
+    // follow the event columns, `--cohorts="test: ...; control: ..."` or
+    // `--cohorts-file=path`, `--attributes=piece,event`, `--max-order=3`,
+    // `--min-support=0.05`, `--metric=risk-ratio`, `--threshold=1.2`,
+    // `--alpha=0.05`, `--batch-size=100000`, `--output=rules.jsonl`,
+    // `--save-sketch=2020-01.json` and `--sketches=2019-12.json,2019-11.json`.
+    // Timely's `-w`, `-n`, `-p` and `-h` options and their values are set
+    // aside for it. Everything else is an input file.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, Box<dyn Error>> {
+        let mut files = Vec::new();
+        let mut header_names = Vec::new();
+        let mut cohorts = DEFAULT_COHORTS.to_string();
+        let mut attributes = None;
+        let mut max_order = None;
+        let mut min_support = 0.05;
+        let mut metric = Metric::RiskRatio;
+        let mut threshold = None;
+        let mut alpha = None;
+        let mut batch_size = None;
+        let mut output = None;
+        let mut save_sketch = None;
+        let mut sketches = Vec::new();
+        let mut timely_args = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if TIMELY_OPTIONS.iter().any(|o| arg.starts_with(o)) {
+                // The value is either attached, as in `-w16`, or the next
+                // argument.
+                if TIMELY_OPTIONS.contains(&arg.as_str()) {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("{} expects a value", arg))?;
+                    timely_args.push(arg);
+                    timely_args.push(value);
+                } else {
+                    timely_args.push(arg);
+                }
+            } else if let Some(headers) = arg.strip_prefix("--headers=") {
+                header_names = split_names(headers);
+            } else if let Some(text) = arg.strip_prefix("--cohorts=") {
+                cohorts = text.to_string();
+            } else if let Some(path) = arg.strip_prefix("--cohorts-file=") {
+                cohorts = fs::read_to_string(path)?;
+            } else if let Some(names) = arg.strip_prefix("--attributes=") {
+                attributes = Some(split_names(names));
+            } else if let Some(n) = arg.strip_prefix("--max-order=") {
+                max_order = Some(n.parse()?);
+            } else if let Some(support) = arg.strip_prefix("--min-support=") {
+                min_support = support.parse()?;
+            } else if let Some(name) = arg.strip_prefix("--metric=") {
+                metric = name.parse()?;
+            } else if let Some(t) = arg.strip_prefix("--threshold=") {
+                threshold = Some(t.parse()?);
+            } else if let Some(a) = arg.strip_prefix("--alpha=") {
+                alpha = Some(a.parse()?);
+            } else if let Some(size) = arg.strip_prefix("--batch-size=") {
+                batch_size = Some(size.parse()?);
+            } else if let Some(path) = arg.strip_prefix("--output=") {
+                output = Some(path.to_string());
+            } else if let Some(path) = arg.strip_prefix("--save-sketch=") {
+                save_sketch = Some(path.to_string());
+            } else if let Some(paths) = arg.strip_prefix("--sketches=") {
+                sketches = split_names(paths);
+            } else {
+                files.push(arg);
+            }
+        }
+
+        let columns: Vec<String> = COLUMNS
+            .iter()
+            .map(|c| c.to_string())
+            .chain(header_names.iter().cloned())
+            .collect();
+        let cohorts = Cohorts::parse(&cohorts, &columns)?;
+
+        let attributes = match attributes {
+            Some(names) => names,
+            None => {
+                let excluded = cohorts.columns();
+                DEFAULT_ATTRIBUTES
+                    .iter()
+                    .map(|c| c.to_string())
+                    .chain(header_names.iter().cloned())
+                    .filter(|name| {
+                        let i = columns.iter().position(|c| c == name).unwrap();
+                        !excluded.contains(&i)
+                    })
+                    .collect()
+            }
+        };
+        let attributes: Vec<usize> = attributes
+            .iter()
+            .map(|name| {
+                columns
+                    .iter()
+                    .position(|c| c == name)
+                    .ok_or_else(|| format!("unknown attribute column: {}", name))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let max_order = max_order.unwrap_or(attributes.len());
+        let threshold = threshold.unwrap_or_else(|| metric.default_threshold());
+
+        Ok(DiffConfig {
+            files,
+            header_names,
+            columns,
+            cohorts,
+            attributes,
+            max_order,
+            min_support,
+            metric,
+            threshold,
+            alpha,
+            batch_size,
+            output,
+            save_sketch,
+            sketches,
+            timely_args,
+        })
+    }
+
+    // Project a row onto the rule attribute columns.
+    pub fn attributes_of(&self, row: &[String]) -> Vec<String> {
+        self.attributes
+            .iter()
+            .map(|i| row.get(*i).cloned().unwrap_or_default())
+            .collect()
+    }
+
+    // Column names of the rule attributes, in rule order.
+    pub fn attribute_names(&self) -> Vec<String> {
+        self.attributes
+            .iter()
+            .map(|i| self.columns[*i].clone())
+            .collect()
+    }
+
+    pub fn combinations(&self) -> Vec<Vec<usize>> {
+        combinations(self.attributes.len(), self.max_order)
+    }
+}
+
+// Every combination of between one and `max_order` of `arity` attribute
+// positions. The empty combination would match every row, so it can't tell
+// the cohorts apart and is left out.
+pub fn combinations(arity: usize, max_order: usize) -> Vec<Vec<usize>> {
+    fn extend(
+        start: usize,
+        arity: usize,
+        max_order: usize,
+        current: &mut Vec<usize>,
+        out: &mut Vec<Vec<usize>>,
+    ) {
+        for i in start..arity {
+            current.push(i);
+            out.push(current.clone());
+            if current.len() < max_order {
+                extend(i + 1, arity, max_order, current, out);
+            }
+            current.pop();
+        }
+    }
+
+    let mut out = Vec::new();
+    if max_order > 0 {
+        extend(0, arity, max_order, &mut Vec::new(), &mut out);
+    }
+    out
+}
+
+// The rules one attribute more specific than `rule` that match `attributes`,
+// each paired with the attributes. Only positions after the last one `rule`
+// specifies are added, so every rule has exactly one parent.
+pub fn extend(
+    attributes: Vec<String>,
+    rule: Vec<Option<String>>,
+) -> Vec<(Vec<String>, Vec<Option<String>>)> {
+    let start = rule.iter().rposition(|a| a.is_some()).map_or(0, |i| i + 1);
+    (start..attributes.len())
+        .map(|i| {
+            let mut extended = rule.clone();
+            extended[i] = Some(attributes[i].clone());
+            (attributes.clone(), extended)
+        })
+        .collect()
+}
+
+// The rules one attribute less specific than `rule`.
+pub fn sub_rules(rule: &[Option<String>]) -> Vec<Vec<Option<String>>> {
+    (0..rule.len())
+        .filter(|i| rule[*i].is_some())
+        .map(|i| {
+            let mut sub = rule.to_vec();
+            sub[i] = None;
+            sub
+        })
+        .collect()
+}
+
+// The rule `combination` picks out of `attributes`, with `None` standing for
+// a wildcard in every other position.
+pub fn rule(attributes: &[String], combination: &[usize]) -> Vec<Option<String>> {
+    let mut rule = vec![None; attributes.len()];
+    for i in combination {
+        rule[*i] = Some(attributes[*i].clone());
+    }
+    rule
+}
+
+fn split_names(names: &str) -> Vec<String> {
+    names
+        .split(',')
+        .filter(|n| !n.is_empty())
+        .map(|n| n.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(args: &[&str]) -> DiffConfig {
+        DiffConfig::from_args(args.iter().map(|a| a.to_string())).unwrap()
+    }
+
+    #[test]
+    fn threshold_defaults_to_the_metric_scale() {
+        assert_eq!(config(&[]).threshold, 1.2);
+        assert_eq!(config(&["--metric=mean-shift"]).threshold, 0.05);
+        assert_eq!(config(&["--metric=chi-squared"]).threshold, 3.84);
+        let given = config(&["--metric=mean-shift", "--threshold=0.2"]);
+        assert_eq!(given.threshold, 0.2);
+    }
+}
@@ -0,0 +1,32 @@
+use std::error::Error;
+use std::fmt;
+
+// Reasons a game can't be replayed. Any of these causes the game to be
+// skipped and reported in the reject log instead of ending the run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GameError {
+    // The SAN doesn't describe a legal move in the current position.
+    IllegalMove(String),
+    // Our piece map disagrees with the position shakmaty is tracking.
+    PieceMapDesync(String),
+    UnsupportedVariant(String),
+    BadFen(String),
+    // The variant needs a FEN header for its start position, and has none.
+    MissingFen(String),
+}
+
+impl fmt::Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameError::IllegalMove(san) => write!(f, "illegal move: {}", san),
+            GameError::PieceMapDesync(detail) => write!(f, "piece map desync: {}", detail),
+            GameError::UnsupportedVariant(variant) => {
+                write!(f, "unsupported variant: {}", variant)
+            }
+            GameError::BadFen(fen) => write!(f, "bad FEN: {}", fen),
+            GameError::MissingFen(variant) => write!(f, "{} game without a FEN", variant),
+        }
+    }
+}
+
+impl Error for GameError {}
@@ -0,0 +1,82 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+
+use crate::output::ColumnarFormat;
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const PARQUET_MAGIC: &[u8] = b"PAR1";
+const ARROW_IPC_MAGIC: &[u8] = b"ARROW1";
+
+// Open `path` (or stdin for "-") and decompress it based on its leading magic
+// bytes rather than its extension. Anything unrecognised is read as plain PGN.
+pub fn open(path: &str) -> io::Result<Box<dyn Read + Send>> {
+    let raw: Box<dyn Read + Send> = if path == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+
+    let mut raw = BufReader::new(raw);
+    let magic: Vec<u8> = raw
+        .fill_buf()?
+        .iter()
+        .take(XZ_MAGIC.len())
+        .copied()
+        .collect();
+
+    let uncompressed: Box<dyn Read + Send> = if magic.starts_with(ZSTD_MAGIC) {
+        let mut decoder = zstd::stream::read::Decoder::with_buffer(raw)?;
+        // Allow the large windows produced by `zstd --long`.
+        decoder.window_log_max(31)?;
+        Box::new(decoder)
+    } else if magic.starts_with(GZIP_MAGIC) {
+        Box::new(flate2::read::MultiGzDecoder::new(raw))
+    } else if magic.starts_with(XZ_MAGIC) {
+        Box::new(xz2::read::XzDecoder::new_multi_decoder(raw))
+    } else if magic.starts_with(BZIP2_MAGIC) {
+        Box::new(bzip2::read::MultiBzDecoder::new(raw))
+    } else {
+        Box::new(raw)
+    };
+
+    Ok(uncompressed)
+}
+
+// The first bytes of `path`, enough to tell the formats we read apart.
+fn magic(path: &str) -> io::Result<Vec<u8>> {
+    let mut magic = Vec::new();
+    File::open(path)?
+        .take(XZ_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    Ok(magic)
+}
+
+// Whether `path` starts with the magic bytes of one of the compression formats
+// `open` understands.
+pub fn is_compressed(path: &str) -> io::Result<bool> {
+    let magic = magic(path)?;
+    Ok([ZSTD_MAGIC, GZIP_MAGIC, XZ_MAGIC, BZIP2_MAGIC]
+        .iter()
+        .any(|m| magic.starts_with(m)))
+}
+
+// The format of `path` if it's a file written by `ColumnarWriter`, judging by
+// its magic bytes. Both formats are read from their footer, so stdin is never
+// taken for one.
+pub fn columnar_format(path: &str) -> io::Result<Option<ColumnarFormat>> {
+    if path == "-" {
+        return Ok(None);
+    }
+
+    let magic = magic(path)?;
+    Ok(if magic.starts_with(PARQUET_MAGIC) {
+        Some(ColumnarFormat::Parquet)
+    } else if magic.starts_with(ARROW_IPC_MAGIC) {
+        Some(ColumnarFormat::ArrowIpc)
+    } else {
+        None
+    })
+}
@@ -0,0 +1,136 @@
+use std::io::{self, BufRead, BufReader, Read};
+
+use pgn_reader::BufferedReader;
+
+pub mod cohort;
+pub mod diff;
+pub mod error;
+pub mod input;
+pub mod metric;
+pub mod output;
+pub mod parallel;
+pub mod report;
+pub mod shard;
+pub mod sketch;
+pub mod stats;
+mod tracker;
+
+pub use error::GameError;
+pub use tracker::{EventKind, FullPiece, Game, LastPosition, PieceEvent, COLUMNS, GAME_KEY_COLUMN};
+
+// Iterator over the games in a PGN stream, yielding the piece events of each.
+pub struct Games<R> {
+    reader: BufferedReader<R>,
+    visitor: LastPosition,
+}
+
+impl<R: Read> Iterator for Games<R> {
+    type Item = io::Result<Game>;
+
+    fn next(&mut self) -> Option<io::Result<Game>> {
+        self.reader.read_game(&mut self.visitor).transpose()
+    }
+}
+
+// Replay every game in `input`, attaching the values of `header_names` to each
+// game and, with `trajectories`, recording every square each piece visits.
+pub fn games<R: Read>(input: R, header_names: Vec<String>, trajectories: bool) -> Games<R> {
+    Games {
+        reader: BufferedReader::new(input),
+        visitor: LastPosition::new(header_names, trajectories),
+    }
+}
+
+// Read piece events from `path` as rows of strings, whether it holds PGN games
+// (possibly compressed), or the comma separated, Parquet or Arrow IPC output of
+// the extractor. Games that can't be replayed are left out. PGN games get one
+// column per entry of `header_names`, like the extractor's `--headers` option,
+// as do columnar files, from the header columns they were written with.
+pub fn event_rows(
+    path: &str,
+    header_names: &[String],
+) -> io::Result<Box<dyn Iterator<Item = io::Result<Vec<String>>>>> {
+    if let Some(format) = input::columnar_format(path)? {
+        let rows = output::columnar_rows(path, format, header_names, |_| true)?;
+        return Ok(Box::new(rows.map(|row| row.map(|(_, row)| row))));
+    }
+
+    let mut input = BufReader::new(input::open(path)?);
+    let is_pgn = is_pgn(&mut input)?;
+    let rows = rows(input, is_pgn, header_names).map(|row| row.map(|(_, row)| row));
+    Ok(Box::new(rows))
+}
+
+// Rows of events paired with the key of the game they come from (see
+// `Game::key`), read from the game itself for PGN and from the row's
+// `game_key` column otherwise. Rows without a valid key get None.
+pub type GameRows = Box<dyn Iterator<Item = io::Result<(Option<u64>, Vec<String>)>>>;
+
+// Whether `input` holds PGN games rather than rows of events, judging by its
+// first character.
+pub(crate) fn is_pgn<R: BufRead>(input: &mut R) -> io::Result<bool> {
+    Ok(input
+        .fill_buf()?
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .map_or(false, |b| *b == b'['))
+}
+
+pub(crate) fn rows<R: BufRead + 'static>(
+    input: R,
+    is_pgn: bool,
+    header_names: &[String],
+) -> GameRows {
+    if is_pgn {
+        let rows = games(input, header_names.to_vec(), false).flat_map(|game| {
+            let rows: Vec<_> = match game {
+                Ok(game) if game.error.is_none() => game
+                    .events
+                    .iter()
+                    .map(|event| Ok((Some(game.key), event.columns(&game))))
+                    .collect(),
+                Ok(_) => Vec::new(),
+                Err(e) => vec![Err(e)],
+            };
+            rows
+        });
+        Box::new(rows)
+    } else {
+        let rows = input.lines().map(|line| {
+            line.map(|l| {
+                let row: Vec<String> = l.split(',').map(|s| s.to_string()).collect();
+                let key = row.get(GAME_KEY_COLUMN).and_then(|key| key.parse().ok());
+                (key, row)
+            })
+        });
+        Box::new(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::output::{CsvWriter, EventWriter};
+
+    #[test]
+    fn extracted_rows_keep_their_game_key() {
+        let pgn = "[Event \"a\"]\n\n1. e4 d5 2. exd5 *\n\n[Event \"b\"]\n\n1. d4 *\n";
+        let read = |input: Vec<u8>, is_pgn| {
+            rows(Cursor::new(input), is_pgn, &[])
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap()
+        };
+        let from_pgn = read(pgn.as_bytes().to_vec(), true);
+
+        let mut csv = Vec::new();
+        let mut writer = CsvWriter::new(&mut csv);
+        for game in games(pgn.as_bytes(), Vec::new(), false) {
+            writer.write_game(0, &game.unwrap()).unwrap();
+        }
+
+        assert!(from_pgn.iter().all(|(key, _)| key.is_some()));
+        assert_eq!(read(csv, false), from_pgn);
+    }
+}
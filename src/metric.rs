@@ -0,0 +1,225 @@
+use std::fmt;
+use std::str::FromStr;
+
+// How often a rule occurs in each cohort, out of how many rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RuleCounts {
+    pub first_rule: u64,
+    pub first_total: u64,
+    pub second_rule: u64,
+    pub second_total: u64,
+}
+
+impl RuleCounts {
+    pub fn first_support(&self) -> f64 {
+        self.first_rule as f64 / self.first_total as f64
+    }
+
+    pub fn second_support(&self) -> f64 {
+        if self.second_total == 0 {
+            0.0
+        } else {
+            self.second_rule as f64 / self.second_total as f64
+        }
+    }
+
+    // The 2x2 contingency table: (first with rule, first without rule,
+    // second with rule, second without rule).
+    pub fn table(&self) -> (f64, f64, f64, f64) {
+        (
+            self.first_rule as f64,
+            (self.first_total - self.first_rule) as f64,
+            self.second_rule as f64,
+            (self.second_total - self.second_rule) as f64,
+        )
+    }
+}
+
+// Rule counts only known to lie within bounds, as when they come from a
+// sketch that may overestimate them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CountBounds {
+    pub lower: RuleCounts,
+    pub upper: RuleCounts,
+}
+
+impl CountBounds {
+    // The counts least favourable to the rule being more common in the first
+    // cohort: as few first cohort rows as possible, and as many second.
+    pub fn worst(&self) -> RuleCounts {
+        RuleCounts {
+            first_rule: self.lower.first_rule,
+            second_rule: self.upper.second_rule,
+            ..self.lower
+        }
+    }
+
+    // The counts most favourable to the rule.
+    pub fn best(&self) -> RuleCounts {
+        RuleCounts {
+            first_rule: self.upper.first_rule,
+            second_rule: self.lower.second_rule,
+            ..self.lower
+        }
+    }
+
+    // The lowest and highest score the rule could have. Every metric grows
+    // with the first cohort's count and shrinks with the second's (chi-squared
+    // because it's 0 for rules no more common in the first cohort), so these
+    // are the scores of the least and most favourable counts.
+    pub fn score_bounds(&self, metric: Metric) -> (f64, f64) {
+        (metric.compute(&self.worst()), metric.compute(&self.best()))
+    }
+}
+
+// Ways of measuring how much more a rule is associated with the first cohort
+// than with the second. Larger is always more interesting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    // P(first | rule) / P(first | not rule)
+    RiskRatio,
+    // Odds of the rule in the first cohort over its odds in the second
+    OddsRatio,
+    // P(rule | first) / P(rule)
+    Lift,
+    // Difference between the rule's support in the first and second cohorts
+    MeanShift,
+    // Pearson's chi-squared statistic for the 2x2 table, or 0 if the rule is
+    // no more common in the first cohort than in the second
+    ChiSquared,
+}
+
+impl Metric {
+    // The score a rule needs to be reported when no threshold is given. Each
+    // metric has its own scale: the ratios are 1 for a rule as common in both
+    // cohorts, the mean shift is 0, and chi-squared is above 3.84 with
+    // probability under 0.05 when the cohorts don't differ.
+    pub fn default_threshold(&self) -> f64 {
+        match self {
+            Metric::RiskRatio | Metric::OddsRatio | Metric::Lift => 1.2,
+            Metric::MeanShift => 0.05,
+            Metric::ChiSquared => 3.84,
+        }
+    }
+
+    pub fn compute(&self, counts: &RuleCounts) -> f64 {
+        let (a, b, c, d) = counts.table();
+
+        match self {
+            Metric::RiskRatio => {
+                if b + d == 0.0 {
+                    0.0
+                } else if b == 0.0 {
+                    f64::INFINITY
+                } else {
+                    (a / (a + c)) / (b / (b + d))
+                }
+            }
+            Metric::OddsRatio => {
+                if a * d == 0.0 {
+                    0.0
+                } else if b * c == 0.0 {
+                    f64::INFINITY
+                } else {
+                    (a * d) / (b * c)
+                }
+            }
+            Metric::Lift => {
+                let n = a + b + c + d;
+                if a == 0.0 {
+                    0.0
+                } else {
+                    (a / (a + b)) / ((a + c) / n)
+                }
+            }
+            Metric::MeanShift => counts.first_support() - counts.second_support(),
+            Metric::ChiSquared => {
+                let n = a + b + c + d;
+                let margins = (a + b) * (c + d) * (a + c) * (b + d);
+                // Only the one direction counts as a difference, as for the
+                // other metrics.
+                if margins == 0.0 || a * d <= b * c {
+                    0.0
+                } else {
+                    n * (a * d - b * c).powi(2) / margins
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "risk-ratio" => Ok(Metric::RiskRatio),
+            "odds-ratio" => Ok(Metric::OddsRatio),
+            "lift" => Ok(Metric::Lift),
+            "mean-shift" => Ok(Metric::MeanShift),
+            "chi-squared" => Ok(Metric::ChiSquared),
+            _ => Err(format!("unknown metric: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Metric::RiskRatio => "risk-ratio",
+            Metric::OddsRatio => "odds-ratio",
+            Metric::Lift => "lift",
+            Metric::MeanShift => "mean-shift",
+            Metric::ChiSquared => "chi-squared",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(first_rule: u64, second_rule: u64) -> RuleCounts {
+        RuleCounts {
+            first_rule,
+            first_total: 100,
+            second_rule,
+            second_total: 100,
+        }
+    }
+
+    #[test]
+    fn chi_squared_only_counts_rules_more_common_in_the_first_cohort() {
+        let score = Metric::ChiSquared.compute(&counts(35, 20));
+        assert!((score - 5.6426332).abs() < 1e-6, "{}", score);
+        assert_eq!(Metric::ChiSquared.compute(&counts(20, 35)), 0.0);
+        assert_eq!(Metric::ChiSquared.compute(&counts(20, 20)), 0.0);
+    }
+
+    #[test]
+    fn score_bounds_hold_between_the_corners() {
+        // A test cohort count anywhere from 10 to 35, against 20 in control.
+        let bounds = CountBounds {
+            lower: counts(10, 20),
+            upper: counts(35, 20),
+        };
+        for metric in [
+            Metric::RiskRatio,
+            Metric::OddsRatio,
+            Metric::Lift,
+            Metric::MeanShift,
+            Metric::ChiSquared,
+        ] {
+            let (low, high) = bounds.score_bounds(metric);
+            for first_rule in 10..=35 {
+                let score = metric.compute(&counts(first_rule, 20));
+                assert!(low <= score && score <= high, "{}: {}", metric, score);
+            }
+        }
+        // Unsigned, chi-squared would be above 3.84 at both corners, though
+        // the rule is as common in both cohorts at 20.
+        assert_eq!(bounds.score_bounds(Metric::ChiSquared).0, 0.0);
+    }
+}
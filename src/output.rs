@@ -0,0 +1,417 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::iter;
+use std::sync::Arc;
+
+use arrow::array::{
+    Array, ArrayRef, AsArray, StringArray, StringBuilder, UInt32Array, UInt32Builder, UInt64Array,
+    UInt64Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, UInt32Type, UInt64Type};
+use arrow::error::ArrowError;
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::file::properties::WriterProperties;
+
+use crate::{Game, GameRows};
+
+// Rows buffered before they are written out as one row group / record batch.
+const ROW_GROUP_SIZE: usize = 1 << 20;
+
+pub trait EventWriter {
+    fn write_game(&mut self, game_id: u64, game: &Game) -> io::Result<()>;
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+fn other_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+// The original comma separated format, see `PieceEvent::columns`.
+pub struct CsvWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(out: W) -> Self {
+        CsvWriter { out }
+    }
+}
+
+impl<W: Write> EventWriter for CsvWriter<W> {
+    fn write_game(&mut self, _game_id: u64, game: &Game) -> io::Result<()> {
+        for event in game.events.iter() {
+            writeln!(self.out, "{}", event.columns(game).join(","))?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ColumnarFormat {
+    Parquet,
+    ArrowIpc,
+}
+
+enum Sink {
+    Parquet(ArrowWriter<File>),
+    ArrowIpc(FileWriter<File>),
+}
+
+// Writes events with a typed schema, one row per event:
+// piece, color, role, starting_file, starting_square, event, square, move, ply,
+// capturer, game, game_key, followed by one nullable string column per
+// requested PGN header.
+// `game` numbers games in the order they were read, like the reject log, and
+// `game_key` is the game's `Game::key`.
+pub struct ColumnarWriter {
+    schema: SchemaRef,
+    sink: Sink,
+    rows: usize,
+    piece: StringBuilder,
+    color: StringBuilder,
+    role: StringBuilder,
+    starting_file: StringBuilder,
+    starting_square: StringBuilder,
+    kind: StringBuilder,
+    square: StringBuilder,
+    moves: UInt32Builder,
+    ply: UInt32Builder,
+    capturer: StringBuilder,
+    game: UInt64Builder,
+    game_key: UInt64Builder,
+    headers: Vec<StringBuilder>,
+}
+
+impl ColumnarWriter {
+    pub fn new(file: File, format: ColumnarFormat, header_names: &[String]) -> io::Result<Self> {
+        let mut fields = vec![
+            Field::new("piece", DataType::Utf8, false),
+            Field::new("color", DataType::Utf8, false),
+            Field::new("role", DataType::Utf8, false),
+            Field::new("starting_file", DataType::Utf8, true),
+            Field::new("starting_square", DataType::Utf8, true),
+            Field::new("event", DataType::Utf8, false),
+            Field::new("square", DataType::Utf8, false),
+            Field::new("move", DataType::UInt32, false),
+            Field::new("ply", DataType::UInt32, false),
+            Field::new("capturer", DataType::Utf8, true),
+            Field::new("game", DataType::UInt64, false),
+            Field::new("game_key", DataType::UInt64, false),
+        ];
+        for name in header_names {
+            fields.push(Field::new(name, DataType::Utf8, true));
+        }
+        let schema = Arc::new(Schema::new(fields));
+
+        let sink = match format {
+            ColumnarFormat::Parquet => {
+                let props = WriterProperties::builder()
+                    .set_max_row_group_size(ROW_GROUP_SIZE)
+                    .build();
+                let writer =
+                    ArrowWriter::try_new(file, schema.clone(), Some(props)).map_err(other_error)?;
+                Sink::Parquet(writer)
+            }
+            ColumnarFormat::ArrowIpc => {
+                let writer = FileWriter::try_new(file, &schema).map_err(other_error)?;
+                Sink::ArrowIpc(writer)
+            }
+        };
+
+        Ok(ColumnarWriter {
+            schema,
+            sink,
+            rows: 0,
+            piece: StringBuilder::new(),
+            color: StringBuilder::new(),
+            role: StringBuilder::new(),
+            starting_file: StringBuilder::new(),
+            starting_square: StringBuilder::new(),
+            kind: StringBuilder::new(),
+            square: StringBuilder::new(),
+            moves: UInt32Builder::new(),
+            ply: UInt32Builder::new(),
+            capturer: StringBuilder::new(),
+            game: UInt64Builder::new(),
+            game_key: UInt64Builder::new(),
+            headers: header_names.iter().map(|_| StringBuilder::new()).collect(),
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(self.piece.finish()),
+            Arc::new(self.color.finish()),
+            Arc::new(self.role.finish()),
+            Arc::new(self.starting_file.finish()),
+            Arc::new(self.starting_square.finish()),
+            Arc::new(self.kind.finish()),
+            Arc::new(self.square.finish()),
+            Arc::new(self.moves.finish()),
+            Arc::new(self.ply.finish()),
+            Arc::new(self.capturer.finish()),
+            Arc::new(self.game.finish()),
+            Arc::new(self.game_key.finish()),
+        ];
+        for header in self.headers.iter_mut() {
+            columns.push(Arc::new(header.finish()));
+        }
+        self.rows = 0;
+
+        let batch = RecordBatch::try_new(self.schema.clone(), columns).map_err(other_error)?;
+        match &mut self.sink {
+            Sink::Parquet(writer) => writer.write(&batch).map_err(other_error),
+            Sink::ArrowIpc(writer) => writer.write(&batch).map_err(other_error),
+        }
+    }
+}
+
+impl EventWriter for ColumnarWriter {
+    fn write_game(&mut self, game_id: u64, game: &Game) -> io::Result<()> {
+        for event in game.events.iter() {
+            self.piece.append_value(event.piece.to_string());
+            self.color.append_value(event.piece.color_name());
+            self.role.append_value(event.piece.role_name());
+            self.starting_file.append_option(
+                event
+                    .piece
+                    .starting_file
+                    .map(|file| file.char().to_ascii_uppercase().to_string()),
+            );
+            self.starting_square
+                .append_option(event.piece.starting_square.map(|s| s.to_string()));
+            self.kind.append_value(event.kind.to_string());
+            self.square.append_value(event.square.to_string());
+            self.moves.append_value(event.moves as u32);
+            self.ply.append_value(event.ply as u32);
+            self.capturer
+                .append_option(event.capturer.map(|c| c.to_string()));
+            self.game.append_value(game_id);
+            self.game_key.append_value(game.key);
+            for (builder, value) in self.headers.iter_mut().zip(game.headers.iter()) {
+                builder.append_option(value.as_deref());
+            }
+            self.rows += 1;
+        }
+
+        if self.rows >= ROW_GROUP_SIZE {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()?;
+
+        let this = *self;
+        match this.sink {
+            Sink::Parquet(writer) => writer.close().map(|_| ()).map_err(other_error),
+            Sink::ArrowIpc(mut writer) => writer.finish().map_err(other_error),
+        }
+    }
+}
+
+// Read the events of a file written by `ColumnarWriter` as rows like the
+// comma separated output, each with the key of its game, with one column per
+// entry of `header_names` (null where the file has no such column). Only the
+// row groups (record batches for Arrow IPC) whose index `part` accepts are
+// read, so workers can share a file without reading all of it.
+pub fn columnar_rows(
+    path: &str,
+    format: ColumnarFormat,
+    header_names: &[String],
+    part: impl Fn(usize) -> bool,
+) -> io::Result<GameRows> {
+    let file = File::open(path)?;
+    let batches: Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>>> = match format {
+        ColumnarFormat::Parquet => {
+            let builder = ParquetRecordBatchReaderBuilder::try_new(file).map_err(other_error)?;
+            let groups = (0..builder.metadata().num_row_groups())
+                .filter(|group| part(*group))
+                .collect();
+            let reader = builder
+                .with_row_groups(groups)
+                .build()
+                .map_err(other_error)?;
+            Box::new(reader)
+        }
+        ColumnarFormat::ArrowIpc => {
+            let mut reader = FileReader::try_new(file, None).map_err(other_error)?;
+            let batches: Vec<usize> = (0..reader.num_batches()).filter(|b| part(*b)).collect();
+            Box::new(
+                batches
+                    .into_iter()
+                    .filter_map(move |batch| match reader.set_index(batch) {
+                        Ok(()) => reader.next(),
+                        Err(e) => Some(Err(e)),
+                    }),
+            )
+        }
+    };
+
+    let header_names = header_names.to_vec();
+    let rows = batches.flat_map(move |batch| -> GameRows {
+        let columns = batch
+            .map_err(other_error)
+            .and_then(|batch| EventColumns::new(&batch, &header_names));
+        match columns {
+            Ok(columns) => Box::new((0..columns.len()).map(move |row| Ok(columns.row(row)))),
+            Err(e) => Box::new(iter::once(Err(e))),
+        }
+    });
+    Ok(Box::new(rows))
+}
+
+// The columns of a record batch that make up event rows.
+struct EventColumns {
+    piece: StringArray,
+    kind: StringArray,
+    square: StringArray,
+    moves: UInt32Array,
+    capturer: StringArray,
+    ply: UInt32Array,
+    game_key: UInt64Array,
+    headers: Vec<Option<StringArray>>,
+}
+
+impl EventColumns {
+    fn new(batch: &RecordBatch, header_names: &[String]) -> io::Result<Self> {
+        let missing = |name: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no {} column of the type ColumnarWriter writes", name),
+            )
+        };
+        let string = |name: &str| {
+            batch
+                .column_by_name(name)
+                .and_then(|column| column.as_string_opt::<i32>())
+                .cloned()
+                .ok_or_else(|| missing(name))
+        };
+        let number = |name: &str| {
+            batch
+                .column_by_name(name)
+                .and_then(|column| column.as_primitive_opt::<UInt32Type>())
+                .cloned()
+                .ok_or_else(|| missing(name))
+        };
+
+        Ok(EventColumns {
+            piece: string("piece")?,
+            kind: string("event")?,
+            square: string("square")?,
+            moves: number("move")?,
+            capturer: string("capturer")?,
+            ply: number("ply")?,
+            game_key: batch
+                .column_by_name("game_key")
+                .and_then(|column| column.as_primitive_opt::<UInt64Type>())
+                .cloned()
+                .ok_or_else(|| missing("game_key"))?,
+            headers: header_names.iter().map(|name| string(name).ok()).collect(),
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.piece.len()
+    }
+
+    // Row `i` with the key of its game, in the columns of `PieceEvent::columns`.
+    fn row(&self, i: usize) -> (Option<u64>, Vec<String>) {
+        let value = |column: &StringArray| {
+            if column.is_null(i) {
+                "null".to_string()
+            } else {
+                column.value(i).to_string()
+            }
+        };
+
+        let mut row = vec![
+            value(&self.piece),
+            value(&self.kind),
+            value(&self.square),
+            self.moves.value(i).to_string(),
+            value(&self.capturer),
+            self.ply.value(i).to_string(),
+            self.game_key.value(i).to_string(),
+        ];
+        for header in self.headers.iter() {
+            // As in the comma separated output, which can't hold commas.
+            row.push(match header {
+                Some(header) => value(header).replace(',', " "),
+                None => "null".to_string(),
+            });
+        }
+
+        (Some(self.game_key.value(i)), row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn columnar_files_read_back_like_the_comma_separated_output() {
+        let pgn = "[Event \"a, b\"]\n[Site \"x\"]\n\n1. e4 d5 2. exd5 Qxd5 *\n";
+        let header_names = vec!["Event".to_string(), "Round".to_string()];
+        let game = crate::games(pgn.as_bytes(), header_names.clone(), true)
+            .next()
+            .unwrap()
+            .unwrap();
+        let expected: Vec<_> = game
+            .events
+            .iter()
+            .map(|event| event.columns(&game))
+            .collect();
+        assert!(!expected.is_empty());
+
+        for (format, extension) in [
+            (ColumnarFormat::Parquet, "parquet"),
+            (ColumnarFormat::ArrowIpc, "arrow"),
+        ] {
+            let path = env::temp_dir().join(format!(
+                "chess-diff-{}-round-trip.{}",
+                std::process::id(),
+                extension
+            ));
+            let path = path.to_str().unwrap();
+
+            let file = File::create(path).unwrap();
+            let mut writer: Box<dyn EventWriter> =
+                Box::new(ColumnarWriter::new(file, format, &header_names).unwrap());
+            writer.write_game(0, &game).unwrap();
+            writer.finish().unwrap();
+
+            let rows: Vec<_> = crate::event_rows(path, &header_names)
+                .unwrap()
+                .collect::<io::Result<_>>()
+                .unwrap();
+            assert_eq!(rows, expected);
+
+            // Another worker's share of the file is empty.
+            let rows = columnar_rows(path, format, &header_names, |part| part != 0).unwrap();
+            assert_eq!(rows.count(), 0);
+
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
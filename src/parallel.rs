@@ -0,0 +1,243 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use pgn_reader::BufferedReader;
+
+use crate::output::EventWriter;
+use crate::{Game, LastPosition};
+
+// Number of games handed to a worker at a time. Chunk boundaries don't depend
+// on the number of threads, so neither does the output.
+const CHUNK_GAMES: usize = 1024;
+
+// A run of whole games, along with the byte offset of the chunk in the
+// decompressed stream and where in the chunk each game starts.
+struct Chunk {
+    index: usize,
+    start: u64,
+    data: Vec<u8>,
+    starts: Vec<usize>,
+}
+
+// Whether `line` is a tag pair like `[Event "..."]`, rather than say a
+// `[%clk ...]` comment wrapped onto a line of its own.
+pub(crate) fn is_tag(line: &[u8]) -> bool {
+    line.starts_with(b"[") && line.get(1).map_or(false, u8::is_ascii_alphabetic)
+}
+
+// Split the decompressed PGN stream into chunks of whole games. A game starts
+// at the first of its tag pairs, whichever tag that is, or at its first move
+// if it has no tags and is the first game in the stream.
+fn split_games<R: BufRead>(mut input: R, chunks: SyncSender<Chunk>) -> io::Result<()> {
+    let mut data = Vec::new();
+    let mut starts = Vec::new();
+    let mut line = Vec::new();
+    let mut offset = 0;
+    let mut start = 0;
+    let mut index = 0;
+    // Whether the last line that wasn't blank was a tag pair, and whether any
+    // game has started yet
+    let mut in_tags = false;
+    let mut started = false;
+
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+
+        if !line.iter().all(u8::is_ascii_whitespace) {
+            let tag = is_tag(&line);
+            if (tag && !in_tags) || !started {
+                if starts.len() == CHUNK_GAMES {
+                    let chunk = Chunk {
+                        index,
+                        start,
+                        data: mem::take(&mut data),
+                        starts: mem::take(&mut starts),
+                    };
+                    if chunks.send(chunk).is_err() {
+                        // The workers have hung up, so there's nobody to read the rest.
+                        return Ok(());
+                    }
+                    index += 1;
+                    start = offset;
+                }
+                starts.push(data.len());
+            }
+            in_tags = tag;
+            started = true;
+        }
+
+        data.extend_from_slice(&line);
+        offset += line.len() as u64;
+    }
+
+    if !data.is_empty() {
+        let _ = chunks.send(Chunk {
+            index,
+            start,
+            data,
+            starts,
+        });
+    }
+
+    Ok(())
+}
+
+// Replay the games of `chunk`, each paired with its offset in the stream.
+// Games are read one at a time from where they start, so a game the splitter
+// didn't see the start of (one without tags, after another game) can only
+// take the offset of the game before it, and not shift any other.
+fn extract_chunk(
+    chunk: &Chunk,
+    header_names: &[String],
+    trajectories: bool,
+) -> io::Result<Vec<(u64, Game)>> {
+    let mut visitor = LastPosition::new(header_names.to_vec(), trajectories);
+    let mut games = Vec::new();
+
+    for (i, game_start) in chunk.starts.iter().enumerate() {
+        let game_end = chunk.starts.get(i + 1).copied().unwrap_or(chunk.data.len());
+        let offset = chunk.start + *game_start as u64;
+        let mut reader = BufferedReader::new(&chunk.data[*game_start..game_end]);
+        while let Some(game) = reader.read_game(&mut visitor)? {
+            games.push((offset, game));
+        }
+    }
+
+    Ok(games)
+}
+
+// Quote a field of a reject line if it would otherwise be split up, the way
+// CSV does: reasons like "expected Rook on e1, found nothing" contain commas.
+fn field(value: &str) -> Cow<'_, str> {
+    if value.contains(&[',', '"', '\n'][..]) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+// Extract every game in `input` on `threads` worker threads, writing games to
+// `writer` in the order they appear in the input. Games that can't be replayed
+// are written to `rejects` as `source,game,offset,reason` lines instead, where
+// the offset is that of the game in the decompressed stream. Sources and
+// reasons with commas or quotes in them are quoted as in CSV.
+#[allow(clippy::too_many_arguments)]
+pub fn extract<R: Read + Send>(
+    input: R,
+    source: &str,
+    threads: usize,
+    header_names: &[String],
+    trajectories: bool,
+    writer: &mut dyn EventWriter,
+    rejects: &mut dyn Write,
+    game_id: &mut u64,
+) -> io::Result<()> {
+    let (chunks_tx, chunks_rx) = mpsc::sync_channel(threads * 2);
+    let (games_tx, games_rx) = mpsc::channel();
+    let chunks_rx: Arc<Mutex<Receiver<Chunk>>> = Arc::new(Mutex::new(chunks_rx));
+
+    thread::scope(|scope| {
+        let splitter = scope.spawn(move || split_games(BufReader::new(input), chunks_tx));
+
+        for _ in 0..threads {
+            let chunks_rx = chunks_rx.clone();
+            let games_tx = games_tx.clone();
+            scope.spawn(move || loop {
+                let next = chunks_rx.lock().unwrap().recv();
+                let chunk = match next {
+                    Ok(chunk) => chunk,
+                    Err(_) => break,
+                };
+
+                let games = extract_chunk(&chunk, header_names, trajectories);
+                if games_tx.send((chunk.index, games)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(chunks_rx);
+        drop(games_tx);
+
+        // Chunks finish out of order, so hold on to them until it's their turn.
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for (index, games) in games_rx {
+            pending.insert(index, games);
+
+            while let Some(games) = pending.remove(&next) {
+                for (offset, game) in games? {
+                    match &game.error {
+                        Some(error) => {
+                            let reason = error.to_string();
+                            writeln!(
+                                rejects,
+                                "{},{},{},{}",
+                                field(source),
+                                game_id,
+                                offset,
+                                field(&reason)
+                            )?;
+                        }
+                        None => writer.write_game(*game_id, &game)?,
+                    }
+                    *game_id += 1;
+                }
+                next += 1;
+            }
+        }
+
+        splitter.join().expect("splitter thread panicked")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::CsvWriter;
+
+    #[test]
+    fn rejects_games_at_their_own_offset() {
+        // The second game has no `[Event` tag, which used to shift the offset
+        // of every later game in its chunk.
+        let pgn = "[Event \"first\"]\n\n1. e4 e5 *\n\n\
+                   [Site \"second\"]\n\n1. d4 d5 *\n\n\
+                   [Event \"third\"]\n\n1. Ke2 *\n";
+        let mut writer = CsvWriter::new(Vec::new());
+        let mut rejects = Vec::new();
+        let mut game_id = 0;
+        extract(
+            pgn.as_bytes(),
+            "games.pgn",
+            2,
+            &[],
+            false,
+            &mut writer,
+            &mut rejects,
+            &mut game_id,
+        )
+        .unwrap();
+
+        let offset = pgn.find("[Event \"third\"]").unwrap();
+        let expected = format!("games.pgn,2,{},illegal move: Ke2\n", offset);
+        assert_eq!(String::from_utf8(rejects).unwrap(), expected);
+        assert_eq!(game_id, 3);
+    }
+
+    #[test]
+    fn quotes_fields_with_commas() {
+        assert_eq!(field("illegal move: Ke2"), "illegal move: Ke2");
+        assert_eq!(
+            field("expected Rook on e1, found nothing"),
+            "\"expected Rook on e1, found nothing\""
+        );
+        assert_eq!(field("a \"b\", c"), "\"a \"\"b\"\", c\"");
+    }
+}
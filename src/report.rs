@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+
+use serde_json::{json, Map, Value};
+
+use crate::metric::{CountBounds, Metric, RuleCounts};
+
+// A rule as it is reported, along with how it was scored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleReport {
+    // One value per attribute column, None where the rule matches anything
+    pub rule: Vec<Option<String>>,
+    pub counts: RuleCounts,
+    pub score: f64,
+    pub risk_ratio_interval: (f64, f64),
+    pub p_value: f64,
+    // Where the number of candidate rules is settled, the p-value corrected
+    // for it
+    pub adjusted_p_value: Option<f64>,
+    // For rules counted with a sketch, the bounds on their counts
+    pub bounds: Option<CountBounds>,
+    // Estimated number of distinct test cohort games the rule was seen in
+    pub games: Option<u64>,
+}
+
+// Writes every change to the set of reported rules as a line of JSON: the
+// rule's attributes by column name, the counts behind it, its score, and
+// whether it was added (diff 1) or retracted (diff -1) after the given batch.
+// Scores that aren't finite, like the risk ratio of a rule never seen in the
+// control cohort, are written as null. Rules counted with a sketch also get
+// the bounds on their counts, and the range of scores those allow, and their
+// p-value corrected for the number of candidate rules.
+pub struct JsonLines<W> {
+    writer: W,
+    // Column name of each rule attribute
+    names: Vec<String>,
+    metric: Metric,
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(writer: W, names: Vec<String>, metric: Metric) -> Self {
+        JsonLines {
+            writer,
+            names,
+            metric,
+        }
+    }
+
+    pub fn write(&mut self, report: &RuleReport, batch: usize, diff: isize) -> io::Result<()> {
+        let rule: Map<String, Value> = self
+            .names
+            .iter()
+            .zip(report.rule.iter())
+            .filter_map(|(name, value)| Some((name.clone(), Value::from(value.clone()?))))
+            .collect();
+        let counts = &report.counts;
+        let (low, high) = report.risk_ratio_interval;
+
+        let mut line = json!({
+            "batch": batch,
+            "diff": diff,
+            "rule": rule,
+            "test": {
+                "count": counts.first_rule,
+                "total": counts.first_total,
+                "support": counts.first_support(),
+            },
+            "control": {
+                "count": counts.second_rule,
+                "total": counts.second_total,
+                "support": counts.second_support(),
+            },
+            "metric": self.metric.to_string(),
+            "score": report.score,
+            "risk_ratio_interval": [low, high],
+            "p_value": report.p_value,
+        });
+
+        if let Some(adjusted) = report.adjusted_p_value {
+            line["adjusted_p_value"] = json!(adjusted);
+        }
+
+        if let Some(games) = report.games {
+            line["test"]["games"] = json!(games);
+        }
+
+        if let Some(bounds) = &report.bounds {
+            let (score_low, score_high) = bounds.score_bounds(self.metric);
+            let (ratio_low, ratio_high) = bounds.score_bounds(Metric::RiskRatio);
+            line["bounds"] = json!({
+                "test": {
+                    "lower": bounds.lower.first_rule,
+                    "upper": bounds.upper.first_rule,
+                },
+                "control": {
+                    "lower": bounds.lower.second_rule,
+                    "upper": bounds.upper.second_rule,
+                },
+                "score": [score_low, score_high],
+                "risk_ratio": [ratio_low, ratio_high],
+            });
+        }
+
+        writeln!(self.writer, "{}", line)
+    }
+}
+
+// Where rules are written: the file at `path`, or stdout. Either way every
+// line is written out as soon as it is complete, so rules can be consumed
+// while the diff is still running. The file is only created once a rule is
+// written, as only one worker ends up with the rules.
+pub fn rule_output(path: Option<&str>) -> Box<dyn Write> {
+    match path {
+        Some(path) => Box::new(LazyFile {
+            path: path.to_string(),
+            file: None,
+        }),
+        None => Box::new(io::stdout()),
+    }
+}
+
+struct LazyFile {
+    path: String,
+    file: Option<LineWriter<File>>,
+}
+
+impl Write for LazyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.file.is_none() {
+            self.file = Some(LineWriter::new(File::create(&self.path)?));
+        }
+        self.file.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
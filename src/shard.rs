@@ -0,0 +1,229 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::iter;
+
+use crate::output::columnar_rows;
+use crate::parallel::is_tag;
+use crate::{input, is_pgn, rows, GameRows};
+
+// One worker's share of the input, out of `peers` workers across every process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub peers: usize,
+}
+
+impl Shard {
+    // The rows of `path`, the `file`th input, that belong to this shard, read
+    // like `event_rows`, each with the key of its game where that's known (see
+    // `GameRows`). Plain files are cut into one byte range per worker on row
+    // boundaries (game boundaries for PGN), so even a single large table is
+    // spread over every worker. Parquet and Arrow IPC files are shared out
+    // by row group (record batch), the workers taking turns. Compressed files
+    // and stdin can only be read from the start, so each of those is read
+    // whole by one worker, taking turns by file.
+    pub fn event_rows(
+        &self,
+        path: &str,
+        file: usize,
+        header_names: &[String],
+    ) -> io::Result<GameRows> {
+        if let Some(format) = input::columnar_format(path)? {
+            let Shard { index, peers } = *self;
+            return columnar_rows(path, format, header_names, |part| part % peers == index);
+        }
+
+        if self.peers == 1 || path == "-" || input::is_compressed(path)? {
+            return if self.peers == 1 || file % self.peers == self.index {
+                let mut input = BufReader::new(input::open(path)?);
+                let pgn = is_pgn(&mut input)?;
+                Ok(rows(input, pgn, header_names))
+            } else {
+                Ok(Box::new(iter::empty()))
+            };
+        }
+
+        let pgn = is_pgn(&mut BufReader::new(File::open(path)?))?;
+        let len = File::open(path)?.metadata()?.len();
+        let start = len * self.index as u64 / self.peers as u64;
+        let end = len * (self.index + 1) as u64 / self.peers as u64;
+
+        let range = ByteRange::open(path, start, end, pgn)?;
+        Ok(rows(BufReader::new(range), pgn, header_names))
+    }
+}
+
+// The whole rows of a file that start within a byte range. For PGN a row is a
+// game, which starts at the first tag pair after its previous game's moves,
+// whichever tag that is, as games are split for the extractor's threads.
+struct ByteRange {
+    input: BufReader<File>,
+    // Offset in the file of the next line to be read
+    offset: u64,
+    end: u64,
+    pgn: bool,
+    // Whether the first row of the range has been found
+    started: bool,
+    // Whether the last line read that wasn't blank was a tag pair
+    in_tags: bool,
+    done: bool,
+    line: Vec<u8>,
+    // How much of `line` has been read
+    pos: usize,
+}
+
+impl ByteRange {
+    fn open(path: &str, start: u64, end: u64, pgn: bool) -> io::Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut offset = 0;
+        let mut in_tags = false;
+        if start > 0 {
+            // Back up a byte, so a line that begins exactly at `start` belongs
+            // to this range rather than the previous one.
+            input.seek(SeekFrom::Start(start - 1))?;
+            offset = start - 1 + input.read_until(b'\n', &mut Vec::new())? as u64;
+            if pgn {
+                in_tags = follows_tag(&mut File::open(path)?, offset)?;
+            }
+        }
+
+        Ok(ByteRange {
+            input,
+            offset,
+            end,
+            pgn,
+            started: start == 0,
+            in_tags,
+            done: false,
+            line: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    // Read the next line of the range into `line`, returning false once the
+    // range is over.
+    fn next_line(&mut self) -> io::Result<bool> {
+        loop {
+            self.line.clear();
+            self.pos = 0;
+
+            let start = self.offset;
+            let n = self.input.read_until(b'\n', &mut self.line)?;
+            self.offset += n as u64;
+
+            let blank = self.line.iter().all(u8::is_ascii_whitespace);
+            let tag = !blank && is_tag(&self.line);
+            let boundary = !self.pgn || (tag && !self.in_tags);
+            if !blank {
+                self.in_tags = tag;
+            }
+            if n == 0 || (boundary && start >= self.end) {
+                self.line.clear();
+                self.done = true;
+                return Ok(false);
+            }
+
+            // Lines before the first boundary finish a game the previous range
+            // started.
+            self.started |= boundary;
+            if self.started {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+// Whether the last line before `offset`, the start of a line, that isn't blank
+// is a tag pair, reading `file` backwards a block at a time. Ranges that start
+// mid-file need this to tell whether their first tag starts a game.
+fn follows_tag(file: &mut File, offset: u64) -> io::Result<bool> {
+    let mut block = vec![0; 4096];
+    let mut end = offset;
+    // Offset of the last byte that isn't whitespace, once found
+    let mut last = None;
+    while end > 0 {
+        let start = end.saturating_sub(block.len() as u64);
+        let bytes = &mut block[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(bytes)?;
+
+        for (i, b) in bytes.iter().enumerate().rev() {
+            if last.is_none() && !b.is_ascii_whitespace() {
+                last = Some(start + i as u64);
+            } else if last.is_some() && *b == b'\n' {
+                return line_is_tag(file, start + i as u64 + 1);
+            }
+        }
+        end = start;
+    }
+
+    match last {
+        Some(_) => line_is_tag(file, 0),
+        None => Ok(false),
+    }
+}
+
+fn line_is_tag(file: &mut File, line_start: u64) -> io::Result<bool> {
+    let mut head = Vec::new();
+    file.seek(SeekFrom::Start(line_start))?;
+    file.take(2).read_to_end(&mut head)?;
+    Ok(is_tag(&head))
+}
+
+impl Read for ByteRange {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.line.len() && (self.done || !self.next_line()?) {
+            return Ok(0);
+        }
+
+        let n = (&self.line[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn pgn_ranges_split_before_a_game_s_first_tag() {
+        // Games whose first tag isn't Event, some with a `[%clk]` comment on a
+        // line of its own.
+        let mut pgn = String::new();
+        for game in 0..30 {
+            if game % 2 == 0 {
+                pgn.push_str(&format!("[Site \"{}\"]\n", game));
+            }
+            pgn.push_str("[Event \"e\"]\n\n1. e4 { [%clk 0:01:00] }\n[%clk 1]\ne5 *\n\n");
+        }
+        let path = env::temp_dir().join(format!("chess-diff-{}-ranges.pgn", std::process::id()));
+        fs::write(&path, &pgn).unwrap();
+        let path = path.to_str().unwrap();
+
+        let len = pgn.len() as u64;
+        for peers in 1..=16 {
+            let mut whole = String::new();
+            for index in 0..peers {
+                let (start, end) = (len * index / peers, len * (index + 1) / peers);
+                let mut range = String::new();
+                ByteRange::open(path, start, end, true)
+                    .unwrap()
+                    .read_to_string(&mut range)
+                    .unwrap();
+                assert!(
+                    range.is_empty() || !whole.trim_end().ends_with(']'),
+                    "{} workers split a game's tags",
+                    peers
+                );
+                whole.push_str(&range);
+            }
+            assert_eq!(whole, pgn, "{} workers", peers);
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+}
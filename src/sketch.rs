@@ -0,0 +1,1207 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::{Add, Sub};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher13;
+
+use crate::metric::{CountBounds, RuleCounts};
+
+// Marks the end of a linked list of buckets or counters.
+const NIL: usize = usize::MAX;
+
+// Decaying sketches scale their counts back down once a weight gets this
+// large, long before it could overflow.
+const MAX_DECAY_SCALE: f64 = 1e150;
+
+// What a sketch counts in: whole numbers for plain counting, or floating point
+// for weighted and decaying sketches.
+pub trait Weight: Copy + PartialOrd + Add<Output = Self> + Sub<Output = Self> + Debug {
+    const ZERO: Self;
+    const ONE: Self;
+    // The smallest weight above zero
+    const MIN_POSITIVE: Self;
+
+    fn scale(self, factor: f64) -> Self;
+}
+
+impl Weight for usize {
+    const ZERO: Self = 0;
+    const ONE: Self = 1;
+    const MIN_POSITIVE: Self = 1;
+
+    fn scale(self, factor: f64) -> Self {
+        (self as f64 * factor).round() as usize
+    }
+}
+
+impl Weight for f64 {
+    const ZERO: Self = 0.0;
+    const ONE: Self = 1.0;
+    const MIN_POSITIVE: Self = f64::MIN_POSITIVE;
+
+    fn scale(self, factor: f64) -> Self {
+        self * factor
+    }
+}
+
+// Exponential time decay, applied as forward decay (Cormode et al.): rather
+// than shrinking every count as time passes, a weight seen at time t is grown
+// by exp(rate * (t - landmark)), which ranks keys the same way. Dividing by
+// the growth at the time of interest gives the decayed counts.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Decay {
+    rate: f64,
+    landmark: f64,
+}
+
+// Approximate counts of the most frequent keys in a stream, keeping at most
+// `limit` counters (Metwally et al.'s SpaceSaving). Each counter holds a count
+// that overestimates the key's true count by at most its error term, and any
+// key that isn't tracked occurred at most as often as the smallest count.
+//
+// Counters live in the stream-summary structure from the same paper: every
+// distinct count has a bucket, buckets form a list in increasing order of
+// count, and each bucket holds a list of the counters with its count. Adding
+// one to a counter moves it to the next bucket along, and the counter to evict
+// is always at the head of the first bucket, so both take constant time.
+// Larger weights may have to pass several buckets. Keys are interned, so each
+// is stored once however many times it's seen.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(into = "Counters<T, W>", from = "Counters<T, W>")]
+#[serde(bound(
+    serialize = "T: Serialize + Clone, W: Weight + Serialize",
+    deserialize = "T: Deserialize<'de> + Ord + Eq + Hash + Clone, W: Weight + Deserialize<'de>"
+))]
+pub struct SpaceSaving<T, W = usize> {
+    // Index of each tracked key's counter
+    ids: HashMap<Arc<[T]>, usize>,
+    counters: Vec<Counter<T, W>>,
+    buckets: Vec<Bucket<W>>,
+    // Buckets that were emptied, to be reused
+    free_buckets: Vec<usize>,
+    // The bucket with the smallest count
+    first_bucket: usize,
+    limit: usize,
+    decay: Option<Decay>,
+}
+
+#[derive(Debug, Clone)]
+struct Counter<T, W> {
+    key: Arc<[T]>,
+    error: W,
+    bucket: usize,
+    // Neighbours in the bucket's list of counters
+    prev: usize,
+    next: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket<W> {
+    count: W,
+    first_counter: usize,
+    // Neighbours in the list of buckets
+    prev: usize,
+    next: usize,
+}
+
+impl<T> SpaceSaving<T>
+where
+    T: Ord + Eq + Hash + Clone,
+{
+    pub fn new(limit: usize) -> Self {
+        SpaceSaving::empty(limit, None)
+    }
+}
+
+impl<T> SpaceSaving<T, f64>
+where
+    T: Ord + Eq + Hash + Clone,
+{
+    // A sketch for `insert_weighted`, e.g. of games weighted by rating.
+    pub fn weighted(limit: usize) -> Self {
+        SpaceSaving::empty(limit, None)
+    }
+
+    // A sketch whose counts halve every `half_life` units of time, for
+    // `insert_at`. Recent keys win out over keys that were only frequent long
+    // ago, as for a rolling sketch of a live game feed.
+    pub fn with_decay(limit: usize, half_life: f64) -> Self {
+        let decay = Decay {
+            rate: std::f64::consts::LN_2 / half_life,
+            landmark: 0.0,
+        };
+        SpaceSaving::empty(limit, Some(decay))
+    }
+
+    // Insert `key` with `weight` as seen at `time`, returning the key it
+    // evicted, if any. Times needn't arrive in order, though a weight from so
+    // long before the landmark that it decays to nothing is still counted as
+    // the smallest positive weight.
+    pub fn insert_at(&mut self, key: Vec<T>, weight: f64, time: f64) -> Option<Vec<T>> {
+        assert!(weight > 0.0, "weights must be positive");
+        let decay = self
+            .decay
+            .expect("insert_at needs a sketch made with with_decay");
+        if decay.rate * (time - decay.landmark) > MAX_DECAY_SCALE.ln() {
+            self.advance(time);
+        }
+
+        let decay = self.decay.unwrap();
+        let growth = (decay.rate * (time - decay.landmark)).exp();
+        self.insert_weighted(key, (weight * growth).max(f64::MIN_POSITIVE))
+    }
+
+    // Every tracked key with its (count, error), decayed to `time`.
+    pub fn decayed_counts(&self, time: f64) -> Vec<(Vec<T>, (f64, f64))> {
+        let factor = match self.decay {
+            Some(decay) => (-decay.rate * (time - decay.landmark)).exp(),
+            None => 1.0,
+        };
+
+        self.get_counts()
+            .into_iter()
+            .map(|(key, (count, error))| (key, (count * factor, error * factor)))
+            .collect()
+    }
+
+    // Move the landmark up to `time`, shrinking every count to match (see
+    // `shrink`). The counters are rebuilt, as counts that were apart may now
+    // be equal.
+    fn advance(&mut self, time: f64) {
+        let decay = self.decay.unwrap();
+        let factor = (-decay.rate * (time - decay.landmark)).exp();
+
+        let empty = SpaceSaving::empty(self.limit, None);
+        let mut counters = Counters::from(mem::replace(self, empty));
+        for (_, counts) in counters.counters.iter_mut() {
+            *counts = shrink(*counts, factor);
+        }
+        counters.decay = Some(Decay {
+            landmark: time,
+            ..decay
+        });
+        *self = SpaceSaving::from(counters);
+    }
+}
+
+// A counter's (count, error) scaled down by `factor`, at most 1, as when its
+// sketch's landmark moves forward. A count that shrinks to nothing is kept at
+// the smallest positive weight, with an error that keeps its lower bound where
+// it was, so keys that were tracked stay tracked.
+fn shrink<W: Weight>((count, error): (W, W), factor: f64) -> (W, W) {
+    let lower = (count - error).scale(factor);
+    let count = count.scale(factor);
+    let count = if count > W::MIN_POSITIVE {
+        count
+    } else {
+        W::MIN_POSITIVE
+    };
+    (count, count - lower)
+}
+
+impl<T, W> SpaceSaving<T, W>
+where
+    T: Ord + Eq + Hash + Clone,
+    W: Weight,
+{
+    fn empty(limit: usize, decay: Option<Decay>) -> Self {
+        SpaceSaving {
+            ids: HashMap::new(),
+            counters: Vec::new(),
+            buckets: Vec::new(),
+            free_buckets: Vec::new(),
+            first_bucket: NIL,
+            limit,
+            decay,
+        }
+    }
+
+    // Count `key` once, returning the key it evicted, if any.
+    pub fn insert(&mut self, key: Vec<T>) -> Option<Vec<T>> {
+        self.insert_weighted(key, W::ONE)
+    }
+
+    // Insert `key` as if it had been seen `weight` times. Weights must be
+    // positive, and may be fractional for a sketch made with `weighted`.
+    pub fn insert_weighted(&mut self, key: Vec<T>, weight: W) -> Option<Vec<T>> {
+        assert!(weight > W::ZERO, "weights must be positive");
+
+        if let Some(&id) = self.ids.get(key.as_slice()) {
+            self.add(id, weight);
+            None
+        } else if self.counters.len() < self.limit {
+            // Easy case, we have not yet filled up our quota of counters
+            let id = self.counters.len();
+            let key: Arc<[T]> = key.into();
+            self.ids.insert(key.clone(), id);
+            self.counters.push(Counter {
+                key,
+                error: W::ZERO,
+                bucket: NIL,
+                prev: NIL,
+                next: NIL,
+            });
+
+            let bucket = self.bucket_from(NIL, weight);
+            self.attach(id, bucket);
+            None
+        } else if self.limit > 0 {
+            // Evict the key with the minimum count, and give its counter to the
+            // new key with that count as the error term
+            let id = self.buckets[self.first_bucket].first_counter;
+            let key: Arc<[T]> = key.into();
+            let old_key = mem::replace(&mut self.counters[id].key, key.clone());
+            self.ids.remove(&old_key);
+            self.ids.insert(key, id);
+            self.counters[id].error = self.buckets[self.first_bucket].count;
+            self.add(id, weight);
+            Some(old_key.to_vec())
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, key: &[T]) -> bool {
+        self.ids.contains_key(key)
+    }
+
+    // The (count, error) of `key`, if it's tracked.
+    pub fn get(&self, key: &[T]) -> Option<(W, W)> {
+        let counter = &self.counters[*self.ids.get(key)?];
+        Some((self.buckets[counter.bucket].count, counter.error))
+    }
+
+    // Every tracked key with its (count, error).
+    pub fn get_counts(&self) -> Vec<(Vec<T>, (W, W))> {
+        self.counters
+            .iter()
+            .map(|c| (c.key.to_vec(), (self.buckets[c.bucket].count, c.error)))
+            .collect()
+    }
+
+    // The most an untracked key can have occurred: the smallest count once
+    // every counter is in use, and zero before that.
+    pub fn min_count(&self) -> W {
+        if self.counters.len() < self.limit || self.first_bucket == NIL {
+            W::ZERO
+        } else {
+            self.buckets[self.first_bucket].count
+        }
+    }
+
+    // Fold `other`, a sketch of another part of the stream, into this one, as
+    // in Agarwal et al.'s mergeable summaries. A key missing from one sketch
+    // is given that sketch's `min_count`, as both count and error, and then
+    // only the `limit` largest counters are kept. Counts still overestimate by
+    // at most their error, and the error of the merged sketch stays within the
+    // sum of the two sketches' errors. Decaying sketches must decay at the
+    // same rate, and are both brought to the later of their landmarks first.
+    pub fn merge(&mut self, other: &SpaceSaving<T, W>) {
+        let (decay, factor, other_factor) = match (self.decay, other.decay) {
+            (Some(decay), Some(other_decay)) => {
+                assert!(decay.rate == other_decay.rate, "decay rates differ");
+                let landmark = decay.landmark.max(other_decay.landmark);
+                let factor = |decay: Decay| (-decay.rate * (landmark - decay.landmark)).exp();
+                let merged = Decay { landmark, ..decay };
+                (Some(merged), factor(decay), factor(other_decay))
+            }
+            (None, None) => (None, 1.0, 1.0),
+            _ => panic!("can't merge decaying and non-decaying sketches"),
+        };
+        let entry = |sketch: &SpaceSaving<T, W>, id: usize, factor: f64| {
+            let counter = &sketch.counters[id];
+            let count = sketch.buckets[counter.bucket].count;
+            shrink((count, counter.error), factor)
+        };
+        // What a key missing from a sketch is given. A sketch that isn't full
+        // has seen no key it doesn't track.
+        let missing = |sketch: &SpaceSaving<T, W>, factor: f64| {
+            let min = sketch.min_count();
+            if min > W::ZERO {
+                shrink((min, min), factor)
+            } else {
+                (W::ZERO, W::ZERO)
+            }
+        };
+
+        let (own_min, other_min) = (missing(self, factor), missing(other, other_factor));
+        let mut merged: Vec<(Vec<T>, (W, W))> = self
+            .ids
+            .iter()
+            .map(|(key, id)| {
+                let (count, error) = entry(self, *id, factor);
+                let (other_count, other_error) = other
+                    .ids
+                    .get(key)
+                    .map_or(other_min, |other_id| entry(other, *other_id, other_factor));
+                (key.to_vec(), (count + other_count, error + other_error))
+            })
+            .collect();
+        merged.extend(
+            other
+                .ids
+                .iter()
+                .filter(|(key, _)| !self.ids.contains_key(*key))
+                .map(|(key, id)| {
+                    let (count, error) = entry(other, *id, other_factor);
+                    (key.to_vec(), (count + own_min.0, error + own_min.1))
+                }),
+        );
+
+        merged.sort_by(|(a, (a_count, _)), (b, (b_count, _))| {
+            b_count
+                .partial_cmp(a_count)
+                .expect("counts must be comparable")
+                .then_with(|| a.cmp(b))
+        });
+        merged.truncate(self.limit);
+        *self = SpaceSaving::from(Counters {
+            limit: self.limit,
+            decay,
+            counters: merged,
+        });
+    }
+
+    // Add `weight` to the count of counter `id`.
+    fn add(&mut self, id: usize, weight: W) {
+        let bucket = self.counters[id].bucket;
+        let count = self.buckets[bucket].count + weight;
+        if count == self.buckets[bucket].count {
+            // Too small a weight to change a floating point count
+            return;
+        }
+
+        let target = self.bucket_from(bucket, count);
+        self.detach(id);
+        self.attach(id, target);
+    }
+
+    // The bucket for `count`, searching forwards from bucket `from` (from the
+    // first bucket if `from` is NIL), which mustn't have a larger count. A new
+    // bucket is made if there isn't one yet.
+    fn bucket_from(&mut self, from: usize, count: W) -> usize {
+        if from != NIL && self.buckets[from].count == count {
+            return from;
+        }
+
+        let mut prev = from;
+        let mut next = if from == NIL {
+            self.first_bucket
+        } else {
+            self.buckets[from].next
+        };
+        // Adding one never has to look further than the next bucket.
+        while next != NIL && self.buckets[next].count < count {
+            prev = next;
+            next = self.buckets[next].next;
+        }
+        if next != NIL && self.buckets[next].count == count {
+            return next;
+        }
+
+        let bucket = Bucket {
+            count,
+            first_counter: NIL,
+            prev,
+            next,
+        };
+        let id = match self.free_buckets.pop() {
+            Some(id) => {
+                self.buckets[id] = bucket;
+                id
+            }
+            None => {
+                self.buckets.push(bucket);
+                self.buckets.len() - 1
+            }
+        };
+
+        if prev == NIL {
+            self.first_bucket = id;
+        } else {
+            self.buckets[prev].next = id;
+        }
+        if next != NIL {
+            self.buckets[next].prev = id;
+        }
+        id
+    }
+
+    // Put counter `id` at the head of `bucket`'s counters.
+    fn attach(&mut self, id: usize, bucket: usize) {
+        let first = self.buckets[bucket].first_counter;
+        if first != NIL {
+            self.counters[first].prev = id;
+        }
+
+        let counter = &mut self.counters[id];
+        counter.bucket = bucket;
+        counter.prev = NIL;
+        counter.next = first;
+        self.buckets[bucket].first_counter = id;
+    }
+
+    // Take counter `id` out of its bucket, freeing the bucket if that leaves it
+    // empty.
+    fn detach(&mut self, id: usize) {
+        let Counter {
+            bucket, prev, next, ..
+        } = self.counters[id];
+        if prev == NIL {
+            self.buckets[bucket].first_counter = next;
+        } else {
+            self.counters[prev].next = next;
+        }
+        if next != NIL {
+            self.counters[next].prev = prev;
+        }
+
+        if self.buckets[bucket].first_counter == NIL {
+            let Bucket { prev, next, .. } = self.buckets[bucket];
+            if prev == NIL {
+                self.first_bucket = next;
+            } else {
+                self.buckets[prev].next = next;
+            }
+            if next != NIL {
+                self.buckets[next].prev = prev;
+            }
+            self.free_buckets.push(bucket);
+        }
+    }
+}
+
+// The serialized form of a sketch: its counters, largest first.
+#[derive(Serialize, Deserialize)]
+pub struct Counters<T, W> {
+    limit: usize,
+    #[serde(default)]
+    decay: Option<Decay>,
+    counters: Vec<(Vec<T>, (W, W))>,
+}
+
+impl<T: Clone, W: Weight> From<SpaceSaving<T, W>> for Counters<T, W> {
+    fn from(sketch: SpaceSaving<T, W>) -> Self {
+        let mut counters = Vec::new();
+        let mut bucket = sketch.first_bucket;
+        while bucket != NIL {
+            let count = sketch.buckets[bucket].count;
+            let mut id = sketch.buckets[bucket].first_counter;
+            while id != NIL {
+                let counter = &sketch.counters[id];
+                counters.push((counter.key.to_vec(), (count, counter.error)));
+                id = counter.next;
+            }
+            bucket = sketch.buckets[bucket].next;
+        }
+        counters.reverse();
+
+        Counters {
+            limit: sketch.limit,
+            decay: sketch.decay,
+            counters,
+        }
+    }
+}
+
+impl<T, W> From<Counters<T, W>> for SpaceSaving<T, W>
+where
+    T: Ord + Eq + Hash + Clone,
+    W: Weight,
+{
+    fn from(counters: Counters<T, W>) -> Self {
+        let mut sketch = SpaceSaving::empty(counters.limit, counters.decay);
+        let mut counters = counters.counters;
+        counters.sort_by(|(_, (a, _)), (_, (b, _))| {
+            a.partial_cmp(b).expect("counts must be comparable")
+        });
+
+        // Build the buckets from the smallest count up, so each new one goes
+        // at the end of the list.
+        let mut last_bucket = NIL;
+        for (key, (count, error)) in counters {
+            let id = sketch.counters.len();
+            let key: Arc<[T]> = key.into();
+            sketch.ids.insert(key.clone(), id);
+            sketch.counters.push(Counter {
+                key,
+                error,
+                bucket: NIL,
+                prev: NIL,
+                next: NIL,
+            });
+
+            last_bucket = sketch.bucket_from(last_bucket, count);
+            sketch.attach(id, last_bucket);
+        }
+        sketch
+    }
+}
+
+// Keys of the Count-Min and HyperLogLog sketches. They're fed to the hasher in
+// an encoding of our own, as what `Hash` feeds a hasher may differ between
+// platforms and Rust releases: integers as little endian bytes, and strings
+// and sequences prefixed by their length.
+pub trait SketchKey {
+    fn write_key<H: Hasher>(&self, hasher: &mut H);
+}
+
+impl SketchKey for u64 {
+    fn write_key<H: Hasher>(&self, hasher: &mut H) {
+        hasher.write(&self.to_le_bytes());
+    }
+}
+
+impl SketchKey for str {
+    fn write_key<H: Hasher>(&self, hasher: &mut H) {
+        (self.len() as u64).write_key(hasher);
+        hasher.write(self.as_bytes());
+    }
+}
+
+impl SketchKey for String {
+    fn write_key<H: Hasher>(&self, hasher: &mut H) {
+        self.as_str().write_key(hasher);
+    }
+}
+
+impl<K: SketchKey> SketchKey for [K] {
+    fn write_key<H: Hasher>(&self, hasher: &mut H) {
+        (self.len() as u64).write_key(hasher);
+        for key in self {
+            key.write_key(hasher);
+        }
+    }
+}
+
+impl<K: SketchKey> SketchKey for Vec<K> {
+    fn write_key<H: Hasher>(&self, hasher: &mut H) {
+        self.as_slice().write_key(hasher);
+    }
+}
+
+// Hash of `key` with SipHash-1-3 keyed by `seed`. Unlike the standard
+// library's hasher, whose algorithm is unspecified, this stays the same across
+// workers, runs and builds, so stored sketches can be merged with new ones.
+pub fn stable_hash<K: SketchKey + ?Sized>(key: &K, seed: u64) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(seed, 0);
+    key.write_key(&mut hasher);
+    hasher.finish()
+}
+
+// Approximate counts of every key in a stream in fixed space (Cormode and
+// Muthukrishnan's Count-Min sketch). Each of `depth` rows of `width` counters
+// is indexed by its own hash of the key, and a key's estimate is the smallest
+// of its counters. Estimates never undercount, and overcount by more than
+// e / width of the stream's total with probability at most exp(-depth).
+//
+// Each row hashes keys with `stable_hash`, seeded by the row's index.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CountMin {
+    width: usize,
+    depth: usize,
+    counts: Vec<u64>,
+}
+
+impl CountMin {
+    pub fn new(width: usize, depth: usize) -> Self {
+        CountMin {
+            width,
+            depth,
+            counts: vec![0; width * depth],
+        }
+    }
+
+    pub fn insert<K: SketchKey + ?Sized>(&mut self, key: &K, count: u64) {
+        for row in 0..self.depth {
+            let i = self.index(row, key);
+            self.counts[i] += count;
+        }
+    }
+
+    pub fn estimate<K: SketchKey + ?Sized>(&self, key: &K) -> u64 {
+        (0..self.depth)
+            .map(|row| self.counts[self.index(row, key)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    // Add the counts of `other`, a sketch of another part of the stream with
+    // the same shape.
+    pub fn merge(&mut self, other: &CountMin) {
+        assert!(
+            (self.width, self.depth) == (other.width, other.depth),
+            "Count-Min sketches differ in shape"
+        );
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other_count;
+        }
+    }
+
+    fn index<K: SketchKey + ?Sized>(&self, row: usize, key: &K) -> usize {
+        let hash = stable_hash(key, row as u64);
+        row * self.width + (hash % self.width as u64) as usize
+    }
+}
+
+// Approximate number of distinct items in a stream (Flajolet et al.'s
+// HyperLogLog), from 2^precision one byte registers. The standard error is
+// about 1.04 / sqrt(2^precision). Items are hashed with `stable_hash`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HyperLogLog {
+    precision: u32,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new(precision: u32) -> Self {
+        assert!((4..=16).contains(&precision), "precision must be 4 to 16");
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    pub fn insert<K: SketchKey + ?Sized>(&mut self, item: &K) {
+        let hash = stable_hash(item, 0);
+
+        // The first bits pick a register, which keeps the longest run of
+        // leading zeros seen in the rest.
+        let register = (hash >> (64 - self.precision)) as usize;
+        let rest = hash << self.precision;
+        let rank = (rest.leading_zeros() + 1).min(64 - self.precision + 1) as u8;
+        self.registers[register] = self.registers[register].max(rank);
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        // Small cardinalities are better estimated from the empty registers.
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    // Take in the items of `other`, which must have the same precision.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        assert!(
+            self.precision == other.precision,
+            "HyperLogLog precisions differ"
+        );
+        for (register, other_register) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *register = (*register).max(*other_register);
+        }
+    }
+}
+
+// Shape of the Count-Min sketch of the second cohort's rules: 4 rows of 4096
+// counters overcount by more than 0.07% of the rule instances with
+// probability under 2%.
+const COUNT_MIN_WIDTH: usize = 4096;
+const COUNT_MIN_DEPTH: usize = 4;
+
+// Precision of the HyperLogLog of the games behind each tracked rule, good to
+// about 6.5%.
+const GAMES_PRECISION: u32 = 8;
+
+// Sketches of the rules in each cohort, with the number of rows behind each.
+// Sketches of separate files or shards can be stored and merged later without
+// reading the games again.
+//
+// Every rule in the second cohort is also counted in a Count-Min sketch, so
+// rules that fall out of its SpaceSaving sketch still have an estimate. Rules
+// tracked in the first cohort's sketch keep a HyperLogLog of the games they
+// were seen in, from when they were last taken into the sketch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CohortSketches {
+    pub first: SpaceSaving<String>,
+    pub first_total: usize,
+    #[serde(with = "entries")]
+    pub first_games: HashMap<Vec<String>, HyperLogLog>,
+    pub second: SpaceSaving<String>,
+    pub second_total: usize,
+    pub second_counts: CountMin,
+}
+
+impl CohortSketches {
+    pub fn new(limit: usize) -> Self {
+        CohortSketches {
+            first: SpaceSaving::new(limit),
+            first_total: 0,
+            first_games: HashMap::new(),
+            second: SpaceSaving::new(limit),
+            second_total: 0,
+            second_counts: CountMin::new(COUNT_MIN_WIDTH, COUNT_MIN_DEPTH),
+        }
+    }
+
+    // Count a rule seen in the first cohort, in the game with key `game` if
+    // it's known (see `Game::key`).
+    pub fn insert_first(&mut self, rule: Vec<String>, game: Option<u64>) {
+        if let Some(evicted) = self.first.insert(rule.clone()) {
+            self.first_games.remove(&evicted);
+        }
+        if let Some(game) = game {
+            self.first_games
+                .entry(rule)
+                .or_insert_with(|| HyperLogLog::new(GAMES_PRECISION))
+                .insert(&game);
+        }
+    }
+
+    pub fn insert_second(&mut self, rule: Vec<String>) {
+        self.second_counts.insert(&rule, 1);
+        self.second.insert(rule);
+    }
+
+    pub fn merge(&mut self, other: &CohortSketches) {
+        self.first.merge(&other.first);
+        self.first_total += other.first_total;
+        for (rule, games) in other.first_games.iter() {
+            match self.first_games.get_mut(rule) {
+                Some(own) => own.merge(games),
+                None => {
+                    self.first_games.insert(rule.clone(), games.clone());
+                }
+            }
+        }
+        let first = &self.first;
+        self.first_games.retain(|rule, _| first.contains(rule));
+
+        self.second.merge(&other.second);
+        self.second_total += other.second_total;
+        self.second_counts.merge(&other.second_counts);
+    }
+
+    // Bounds on the counts of every rule tracked in the first cohort's sketch,
+    // with the number of distinct games it was seen in where that's known. A
+    // rule the second cohort's sketch doesn't track may still have occurred
+    // there, up to its Count-Min estimate or the sketch's smallest count,
+    // whichever is lower.
+    pub fn rule_bounds(&self) -> Vec<(Vec<String>, CountBounds, Option<u64>)> {
+        self.first
+            .get_counts()
+            .into_iter()
+            .map(|(rule, (count, error))| {
+                let (second_low, second_high) = match self.second.get(&rule) {
+                    Some((count, error)) => (count - error, count),
+                    None => {
+                        let estimate = self.second_counts.estimate(&rule) as usize;
+                        (0, estimate.min(self.second.min_count()))
+                    }
+                };
+                let counts = |first_rule: usize, second_rule: usize| RuleCounts {
+                    first_rule: first_rule as u64,
+                    first_total: self.first_total as u64,
+                    second_rule: second_rule as u64,
+                    second_total: self.second_total as u64,
+                };
+                let bounds = CountBounds {
+                    lower: counts(count - error, second_low),
+                    upper: counts(count, second_high),
+                };
+                let games = self.first_games.get(&rule).map(HyperLogLog::estimate);
+                (rule, bounds, games)
+            })
+            .collect()
+    }
+}
+
+// Serializes a map with keys JSON can't use as object keys as a list of
+// entries.
+mod entries {
+    use std::collections::HashMap;
+    use std::hash::Hash;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let entries: Vec<(K, V)> = Vec::deserialize(deserializer)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> Vec<String> {
+        vec![name.to_string()]
+    }
+
+    // A reproducible stream of keys, from a linear congruential generator,
+    // where a few keys are common and most are rare.
+    fn stream(seed: u64, len: usize) -> Vec<Vec<String>> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                let r = (state >> 33) % 1000;
+                key(&(r * r / 2000).to_string())
+            })
+            .collect()
+    }
+
+    fn exact_counts(keys: &[Vec<String>]) -> HashMap<Vec<String>, usize> {
+        let mut counts = HashMap::new();
+        for key in keys {
+            *counts.entry(key.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    // Every key's true count lies within the bounds the sketch gives it.
+    fn assert_bounds(sketch: &SpaceSaving<String>, exact: &HashMap<Vec<String>, usize>) {
+        for (key, count) in exact.iter() {
+            match sketch.get(key) {
+                Some((upper, error)) => assert!(
+                    (upper - error..=upper).contains(count),
+                    "{:?}: {} outside {}..={}",
+                    key,
+                    count,
+                    upper - error,
+                    upper
+                ),
+                None => assert!(*count <= sketch.min_count(), "{:?}: {}", key, count),
+            }
+        }
+    }
+
+    fn sorted_counts<W: Weight>(sketch: &SpaceSaving<String, W>) -> Vec<(Vec<String>, (W, W))> {
+        let mut counts = sketch.get_counts();
+        counts.sort_by(|(a, _), (b, _)| a.cmp(b));
+        counts
+    }
+
+    // Check the stream-summary's links: buckets in increasing order of count,
+    // none of them empty or also free, and each counter listed once, in the
+    // bucket it points back to.
+    fn assert_structure<W: Weight>(sketch: &SpaceSaving<String, W>) {
+        let mut listed = 0;
+        let mut live = Vec::new();
+        let mut prev = NIL;
+        let mut bucket = sketch.first_bucket;
+        while bucket != NIL {
+            let Bucket {
+                count,
+                first_counter,
+                prev: bucket_prev,
+                next,
+            } = sketch.buckets[bucket];
+            assert_eq!(bucket_prev, prev);
+            assert!(prev == NIL || sketch.buckets[prev].count < count);
+            assert_ne!(first_counter, NIL, "bucket {} is empty", bucket);
+
+            let (mut id, mut prev_counter) = (first_counter, NIL);
+            while id != NIL {
+                let counter = &sketch.counters[id];
+                assert_eq!((counter.bucket, counter.prev), (bucket, prev_counter));
+                assert_eq!(sketch.ids[&counter.key], id);
+                listed += 1;
+                prev_counter = id;
+                id = counter.next;
+            }
+
+            live.push(bucket);
+            prev = bucket;
+            bucket = next;
+        }
+
+        assert_eq!(listed, sketch.counters.len());
+        assert_eq!(sketch.ids.len(), sketch.counters.len());
+        assert!(live.iter().all(|b| !sketch.free_buckets.contains(b)));
+        assert_eq!(live.len() + sketch.free_buckets.len(), sketch.buckets.len());
+    }
+
+    // SpaceSaving kept in a plain list. Of the counters with the smallest
+    // count, the one evicted is the one that reached it last, as the
+    // stream-summary keeps each bucket's newest counter at its head.
+    struct NaiveSpaceSaving {
+        // (key, count, error, when the count last changed)
+        counters: Vec<(Vec<String>, usize, usize, usize)>,
+        limit: usize,
+        time: usize,
+    }
+
+    impl NaiveSpaceSaving {
+        fn insert_weighted(&mut self, key: Vec<String>, weight: usize) {
+            self.time += 1;
+            if let Some(counter) = self.counters.iter_mut().find(|c| c.0 == key) {
+                counter.1 += weight;
+                counter.3 = self.time;
+            } else if self.counters.len() < self.limit {
+                self.counters.push((key, weight, 0, self.time));
+            } else {
+                let min = self
+                    .counters
+                    .iter_mut()
+                    .min_by_key(|c| (c.1, usize::MAX - c.3))
+                    .unwrap();
+                *min = (key, min.1 + weight, min.1, self.time);
+            }
+        }
+
+        fn sorted_counts(&self) -> Vec<(Vec<String>, (usize, usize))> {
+            let mut counts: Vec<_> = self
+                .counters
+                .iter()
+                .map(|(key, count, error, _)| (key.clone(), (*count, *error)))
+                .collect();
+            counts.sort_by(|(a, _), (b, _)| a.cmp(b));
+            counts
+        }
+    }
+
+    #[test]
+    fn stream_summary_matches_a_naive_sketch() {
+        for (limit, weights) in [(1, 1), (8, 1), (8, 7), (30, 20)] {
+            let mut sketch = SpaceSaving::new(limit);
+            let mut naive = NaiveSpaceSaving {
+                counters: Vec::new(),
+                limit,
+                time: 0,
+            };
+            for (i, key) in stream(limit as u64, 3000).into_iter().enumerate() {
+                // Weights above one jump over several buckets at a time.
+                let weight = 1 + i * 7919 % weights;
+                sketch.insert_weighted(key.clone(), weight);
+                naive.insert_weighted(key, weight);
+                if i % 100 == 0 {
+                    assert_structure(&sketch);
+                }
+            }
+
+            assert_structure(&sketch);
+            assert!(sketch.buckets.len() > 1);
+            assert_eq!(sorted_counts(&sketch), naive.sorted_counts());
+        }
+    }
+
+    #[test]
+    fn weighted_sketches_keep_their_structure() {
+        let mut sketch = SpaceSaving::weighted(10);
+        for (i, key) in stream(3, 3000).into_iter().enumerate() {
+            sketch.insert_weighted(key, 0.5 + (i % 13) as f64 * 0.25);
+        }
+        assert_structure(&sketch);
+
+        let read = SpaceSaving::from(Counters::from(sketch.clone()));
+        assert_structure(&read);
+        assert_eq!(sorted_counts(&read), sorted_counts(&sketch));
+    }
+
+    #[test]
+    fn merged_sketches_keep_their_guarantees() {
+        for (limit, len) in [(5, 200), (20, 2000), (50, 5000), (1000, 3000)] {
+            let (first, second) = (stream(limit as u64, len), stream(len as u64, len / 2));
+            let mut sketch = SpaceSaving::new(limit);
+            let mut other = SpaceSaving::new(limit);
+            for key in first.iter() {
+                sketch.insert(key.clone());
+            }
+            for key in second.iter() {
+                other.insert(key.clone());
+            }
+            assert_bounds(&sketch, &exact_counts(&first));
+            assert_bounds(&other, &exact_counts(&second));
+
+            sketch.merge(&other);
+            assert!(sketch.get_counts().len() <= limit);
+            let both: Vec<_> = first.into_iter().chain(second).collect();
+            assert_bounds(&sketch, &exact_counts(&both));
+        }
+    }
+
+    #[test]
+    fn sketches_survive_serialization() {
+        let keys = stream(1, 2000);
+        let mut sketch = SpaceSaving::new(20);
+        for key in keys[..1000].iter() {
+            sketch.insert(key.clone());
+        }
+
+        let text = serde_json::to_string(&sketch).unwrap();
+        let mut read: SpaceSaving<String> = serde_json::from_str(&text).unwrap();
+        assert_eq!(sorted_counts(&read), sorted_counts(&sketch));
+        assert_eq!(read.min_count(), sketch.min_count());
+
+        // The sketch read back carries on counting with the same guarantees.
+        for key in keys[1000..].iter() {
+            read.insert(key.clone());
+        }
+        assert_bounds(&read, &exact_counts(&keys));
+    }
+
+    #[test]
+    fn stable_hash_is_pinned() {
+        // Stored sketches depend on these never changing. The values are
+        // SipHash-1-3 of the length prefixed encoding, as computed by an
+        // independent implementation.
+        assert_eq!(stable_hash("e4", 0), 0xd3f4_9c78_80d1_b6f2);
+        assert_eq!(stable_hash(&1u64, 0), 0x1e9f_7341_61d6_2dd9);
+        let rule = vec!["White-Pawn-E".to_string(), "*".to_string()];
+        assert_eq!(stable_hash(&rule, 3), 0x63bc_6c1e_f662_e8a8);
+    }
+
+    #[test]
+    fn count_min_never_undercounts() {
+        let count = |i: u64| i % 7 + 1;
+        let mut sketch = CountMin::new(64, 4);
+        let mut other = CountMin::new(64, 4);
+        for i in 0..1000u64 {
+            sketch.insert(&i, count(i));
+            other.insert(&i, count(i));
+        }
+        assert!((0..1000u64).all(|i| sketch.estimate(&i) >= count(i)));
+
+        sketch.merge(&other);
+        assert!((0..1000u64).all(|i| sketch.estimate(&i) >= 2 * count(i)));
+        assert_eq!(CountMin::new(64, 4).estimate(&0u64), 0);
+    }
+
+    #[test]
+    fn hyper_log_log_estimates_distinct_items() {
+        let mut sketch = HyperLogLog::new(GAMES_PRECISION);
+        let mut other = HyperLogLog::new(GAMES_PRECISION);
+        for i in 0..10_000u64 {
+            // Every item is seen twice, which mustn't change the estimate.
+            sketch.insert(&i);
+            sketch.insert(&i);
+            other.insert(&(i + 5000));
+        }
+        // Within three standard errors of the 6.5% the precision gives.
+        let estimate = sketch.estimate() as f64;
+        assert!((estimate - 10_000.0).abs() < 0.2 * 10_000.0, "{}", estimate);
+
+        sketch.merge(&other);
+        let estimate = sketch.estimate() as f64;
+        assert!((estimate - 15_000.0).abs() < 0.2 * 15_000.0, "{}", estimate);
+
+        // Small counts come from the empty registers, and are close to exact.
+        let mut small = HyperLogLog::new(GAMES_PRECISION);
+        for i in 0..10u64 {
+            small.insert(&i);
+        }
+        assert_eq!(small.estimate(), 10);
+    }
+
+    #[test]
+    fn games_are_counted_per_rule() {
+        let mut sketches = CohortSketches::new(10);
+        for game in 0..50u64 {
+            for _ in 0..3 {
+                sketches.insert_first(key("a"), Some(game));
+            }
+            sketches.first_total += 3;
+        }
+        sketches.insert_second(key("a"));
+        sketches.second_total += 1;
+
+        let bounds = sketches.rule_bounds();
+        assert_eq!(bounds.len(), 1);
+        let (rule, bounds, games) = &bounds[0];
+        assert_eq!(rule, &key("a"));
+        assert_eq!(bounds.upper.first_rule, 150);
+        assert_eq!(bounds.upper.second_rule, 1);
+        let games = games.unwrap() as f64;
+        assert!((games - 50.0).abs() < 0.2 * 50.0, "{}", games);
+    }
+
+    #[test]
+    fn decay_accepts_times_far_before_the_landmark() {
+        let mut sketch = SpaceSaving::with_decay(2, 1.0);
+        sketch.insert_at(key("old"), 1.0, 0.0);
+        // Far enough ahead to move the landmark, after which a weight from
+        // time 0 decays to nothing.
+        sketch.insert_at(key("new"), 1.0, 2000.0);
+        sketch.insert_at(key("old"), 1.0, 0.0);
+        sketch.insert_at(key("older"), 1.0, -2000.0);
+
+        let counts = sketch.decayed_counts(2000.0);
+        assert_eq!(counts.len(), 2);
+        assert!(counts.contains(&(key("new"), (1.0, 0.0))));
+        assert!(counts.iter().all(|(_, (count, _))| *count > 0.0));
+    }
+
+    #[test]
+    fn merging_brings_decaying_sketches_to_the_later_landmark() {
+        let mut sketch = SpaceSaving::with_decay(2, 1.0);
+        sketch.insert_at(key("a"), 1.0, 0.0);
+        // This sketch's landmark moves to 2000.
+        let mut other = SpaceSaving::with_decay(2, 1.0);
+        other.insert_at(key("b"), 1.0, 0.0);
+        other.insert_at(key("c"), 1.0, 2000.0);
+
+        sketch.merge(&other);
+        let finite = |sketch: &SpaceSaving<String, f64>| {
+            sketch
+                .get_counts()
+                .iter()
+                .all(|(_, (count, error))| count.is_finite() && error.is_finite())
+        };
+        assert!(finite(&sketch));
+        let counts = sketch.decayed_counts(2000.0);
+        assert!(counts.contains(&(key("c"), (1.0, 0.0))), "{:?}", counts);
+
+        // The merged sketch carries on, and can be stored.
+        sketch.insert_at(key("d"), 1.0, 2001.0);
+        assert!(finite(&sketch));
+        assert!(sketch.contains(&key("d")));
+        let text = serde_json::to_string(&sketch).unwrap();
+        let read: SpaceSaving<String, f64> = serde_json::from_str(&text).unwrap();
+        assert_eq!(sorted_counts(&read), sorted_counts(&sketch));
+
+        // Merging in a sketch with an earlier landmark keeps the later one.
+        other.merge(&SpaceSaving::with_decay(2, 1.0));
+        assert_eq!(other.decayed_counts(2000.0), other.get_counts());
+    }
+
+    #[test]
+    fn decay_survives_a_huge_jump_in_time() {
+        let mut sketch = SpaceSaving::with_decay(3, 1.0);
+        sketch.insert_at(key("a"), 2.0, 0.0);
+        sketch.insert_at(key("b"), 1.0, 0.0);
+        sketch.insert_at(key("c"), 1.0, 1e6);
+
+        let (count, error) = sketch.get(&key("c")).unwrap();
+        assert_eq!((count, error), (1.0, 0.0));
+        // The old keys are still tracked, with counts that can't go below
+        // what they really decayed to.
+        for name in ["a", "b"] {
+            let (count, error) = sketch.get(&key(name)).unwrap();
+            assert!(count > 0.0 && count.is_finite());
+            assert!(count - error <= f64::MIN_POSITIVE);
+        }
+    }
+}
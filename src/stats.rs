@@ -0,0 +1,185 @@
+use crate::metric::RuleCounts;
+
+// z score for a two sided 95% confidence interval
+const Z_95: f64 = 1.959_963_984_540_054;
+
+// Below this expected cell count the chi-squared approximation is poor, so we
+// fall back to Fisher's exact test.
+const MIN_EXPECTED: f64 = 5.0;
+
+impl RuleCounts {
+    // 95% confidence interval for the risk ratio, using the normal
+    // approximation to its logarithm. Empty cells get the usual 0.5 correction
+    // so that rare rules get a wide interval rather than an infinite one.
+    pub fn risk_ratio_interval(&self) -> (f64, f64) {
+        let (mut a, mut b, mut c, mut d) = self.table();
+        if a == 0.0 || b == 0.0 || c == 0.0 || d == 0.0 {
+            a += 0.5;
+            b += 0.5;
+            c += 0.5;
+            d += 0.5;
+        }
+
+        let ratio = (a / (a + c)) / (b / (b + d));
+        let se = (1.0 / a - 1.0 / (a + c) + 1.0 / b - 1.0 / (b + d)).sqrt();
+        (
+            (ratio.ln() - Z_95 * se).exp(),
+            (ratio.ln() + Z_95 * se).exp(),
+        )
+    }
+
+    // Two sided p-value for the rule being independent of the cohort.
+    pub fn p_value(&self) -> f64 {
+        let (a, b, c, d) = self.table();
+        let n = a + b + c + d;
+        let expected = [
+            (a + b) * (a + c) / n,
+            (a + b) * (b + d) / n,
+            (c + d) * (a + c) / n,
+            (c + d) * (b + d) / n,
+        ];
+
+        if expected.iter().any(|e| *e < MIN_EXPECTED) {
+            fisher_exact(a as u64, b as u64, c as u64, d as u64)
+        } else {
+            let margins = (a + b) * (c + d) * (a + c) * (b + d);
+            let chi_squared = n * (a * d - b * c).powi(2) / margins;
+            // Survival function of the chi-squared distribution with one
+            // degree of freedom.
+            erfc((chi_squared / 2.0).sqrt())
+        }
+    }
+}
+
+// Bonferroni adjusted p-value for one of `tests` simultaneous tests.
+pub fn bonferroni(p_value: f64, tests: u64) -> f64 {
+    (p_value * tests as f64).min(1.0)
+}
+
+// Two sided Fisher's exact test for the table [[a, b], [c, d]].
+fn fisher_exact(a: u64, b: u64, c: u64, d: u64) -> f64 {
+    let (row, col, n) = (a + b, a + c, a + b + c + d);
+    let ln_choose = |n: u64, k: u64| ln_factorial(n) - ln_factorial(k) - ln_factorial(n - k);
+    // log P(first cell = k) given the margins
+    let ln_p = |k: u64| ln_choose(row, k) + ln_choose(n - row, col - k) - ln_choose(n, col);
+
+    let observed = ln_p(a);
+    let low = col.saturating_sub(n - row);
+    let high = row.min(col);
+    let p: f64 = (low..=high)
+        .map(ln_p)
+        // Allow for rounding error when comparing equally likely tables.
+        .filter(|p| *p <= observed + 1e-7)
+        .map(f64::exp)
+        .sum();
+    p.min(1.0)
+}
+
+fn ln_factorial(n: u64) -> f64 {
+    ln_gamma(n as f64 + 1.0)
+}
+
+// Lanczos approximation of ln(Gamma(x)) for x >= 0.5.
+fn ln_gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    let x = x - 1.0;
+    let t = x + G + 0.5;
+    let sum = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as f64 + 1.0)
+        });
+
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+// Complementary error function, accurate to about 1e-7.
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t
+        * (-z * z - 1.265_512_23
+            + t * (1.000_023_68
+                + t * (0.374_091_96
+                    + t * (0.096_784_18
+                        + t * (-0.186_288_06
+                            + t * (0.278_868_07
+                                + t * (-1.135_203_98
+                                    + t * (1.488_515_87
+                                        + t * (-0.822_152_23 + t * 0.170_872_77)))))))))
+            .exp();
+
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(a: u64, b: u64, c: u64, d: u64) -> RuleCounts {
+        RuleCounts {
+            first_rule: a,
+            first_total: a + b,
+            second_rule: c,
+            second_total: c + d,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() < tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn small_tables_use_fishers_exact_test() {
+        // Fisher's tea tasting experiment: 34/70 of the tables with these
+        // margins are as extreme as this one.
+        assert_close(counts(3, 1, 1, 3).p_value(), 34.0 / 70.0, 1e-9);
+        assert_close(fisher_exact(3, 1, 1, 3), fisher_exact(1, 3, 3, 1), 1e-12);
+    }
+
+    #[test]
+    fn large_tables_use_the_chi_squared_test() {
+        // Chi-squared is 25/3, with one degree of freedom.
+        assert_close(counts(50, 50, 30, 70).p_value(), 0.003_892_417, 1e-6);
+    }
+
+    #[test]
+    fn risk_ratio_interval_surrounds_the_ratio() {
+        let (low, high) = counts(20, 80, 10, 90).risk_ratio_interval();
+        assert_close(low, 1.050_463_925, 1e-6);
+        assert_close(high, 1.910_531_524, 1e-6);
+
+        // A rule never seen in the second cohort gets a finite interval.
+        let (low, high) = counts(5, 95, 0, 100).risk_ratio_interval();
+        assert!(low.is_finite() && high.is_finite() && low < high);
+    }
+
+    #[test]
+    fn bonferroni_caps_at_one() {
+        assert_close(bonferroni(0.001, 20), 0.02, 1e-12);
+        assert_eq!(bonferroni(0.2, 20), 1.0);
+    }
+}
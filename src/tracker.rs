@@ -0,0 +1,713 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::Hasher;
+use std::mem;
+
+use shakmaty::fen::Fen;
+use shakmaty::{
+    Board, CastlingSide, Chess, Color, File, Move, Piece, Position, Rank, Role, Square,
+};
+
+use pgn_reader::{Outcome, RawHeader, SanPlus, Skip, Visitor};
+use siphasher::sip::SipHasher13;
+
+use crate::error::GameError;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FullPiece {
+    pub color: Color,
+    pub role: Role,
+    pub starting_file: Option<File>,
+    // Where the piece stood when the game started, for games set up from a
+    // position other than a standard or Chess960 start
+    pub starting_square: Option<Square>,
+}
+
+impl FullPiece {
+    pub fn color_name(&self) -> &'static str {
+        match self.color {
+            Color::Black => "Black",
+            Color::White => "White",
+        }
+    }
+
+    pub fn role_name(&self) -> &'static str {
+        match self.role {
+            Role::Bishop => "Bishop",
+            Role::King => "King",
+            Role::Knight => "Knight",
+            Role::Pawn => "Pawn",
+            Role::Queen => "Queen",
+            Role::Rook => "Rook",
+        }
+    }
+}
+
+impl fmt::Display for FullPiece {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.color_name(), self.role_name())?;
+
+        if let Some(file) = self.starting_file {
+            write!(f, "-{}", file.char().to_uppercase())?;
+        } else if let Some(square) = self.starting_square {
+            write!(f, "-{}", square.to_string().to_uppercase())?;
+        };
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Captured,
+    Promoted,
+    Survived,
+    Castled(CastlingSide),
+    GaveCheck,
+    DeliveredMate,
+    Started,
+    Moved,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            EventKind::Captured => "captured",
+            EventKind::Promoted => "promoted",
+            EventKind::Survived => "survived",
+            EventKind::Castled(CastlingSide::KingSide) => "castled_kingside",
+            EventKind::Castled(CastlingSide::QueenSide) => "castled_queenside",
+            EventKind::GaveCheck => "gave_check",
+            EventKind::DeliveredMate => "delivered_mate",
+            EventKind::Started => "started",
+            EventKind::Moved => "moved",
+        };
+
+        write!(f, "{}", kind)
+    }
+}
+
+#[derive(Debug)]
+pub struct PieceEvent {
+    pub piece: FullPiece,
+    pub kind: EventKind,
+    pub square: Square,
+    pub moves: usize,
+    pub ply: usize,
+    pub capturer: Option<FullPiece>,
+}
+
+// Names of the columns of an event row, which are followed by one column per
+// requested PGN header.
+pub const COLUMNS: &[&str] = &[
+    "piece", "event", "square", "move", "capturer", "ply", "game_key",
+];
+
+// Index of the column holding the key of the event's game.
+pub const GAME_KEY_COLUMN: usize = 6;
+
+// The tags PGN requires of every game, which along with its moves tell games
+// apart.
+const SEVEN_TAG_ROSTER: &[&[u8]] = &[
+    b"Event", b"Site", b"Date", b"Round", b"White", b"Black", b"Result",
+];
+
+impl PieceEvent {
+    // The event as a row of the comma separated output:
+    // piece,event,square,move,capturer,ply,game_key[,header...]
+    pub fn columns(&self, game: &Game) -> Vec<String> {
+        let mut columns = vec![
+            self.piece.to_string(),
+            self.kind.to_string(),
+            self.square.to_string(),
+            self.moves.to_string(),
+            self.capturer
+                .map(|c| c.to_string())
+                .unwrap_or_else(|| "null".to_string()),
+            self.ply.to_string(),
+            game.key.to_string(),
+        ];
+
+        for value in game.headers.iter() {
+            // Rows are comma separated, so commas can't appear in values.
+            columns.push(match value {
+                Some(value) => value.replace(',', " "),
+                None => "null".to_string(),
+            });
+        }
+
+        columns
+    }
+}
+
+// Everything extracted from a single game.
+#[derive(Debug)]
+pub struct Game {
+    // Hash of the game's Seven Tag Roster and mainline moves, which is the
+    // same whichever file, worker or run the game is read in
+    pub key: u64,
+    pub headers: Vec<Option<String>>,
+    pub events: Vec<PieceEvent>,
+    pub error: Option<GameError>,
+}
+
+pub struct LastPosition {
+    pos: Chess,
+    pieces: BTreeMap<Square, FullPiece>,
+    // Full move number and ply of the last move played, counted from the
+    // start of the game (or from the move number in its FEN)
+    moves: usize,
+    ply: usize,
+    // Record every square a piece visits, not just where it ended up
+    trajectories: bool,
+    error: Option<GameError>,
+    setup: bool,
+    chess960: bool,
+    // PGN headers to attach to every row, and their values in the current game
+    header_names: Vec<String>,
+    header_values: Vec<Option<String>>,
+    // Hasher for the key of the current game
+    key: SipHasher13,
+    events: Vec<PieceEvent>,
+}
+
+impl LastPosition {
+    pub fn new(header_names: Vec<String>, trajectories: bool) -> LastPosition {
+        let header_values = vec![None; header_names.len()];
+        LastPosition {
+            pos: Chess::default(),
+            pieces: BTreeMap::new(),
+            moves: 0,
+            ply: 0,
+            trajectories,
+            error: None,
+            setup: false,
+            chess960: false,
+            header_names,
+            header_values,
+            key: SipHasher13::new(),
+            events: Vec::new(),
+        }
+    }
+
+    // Add a tag or move to the game's key, prefixed by its length so that
+    // fields can't run into each other.
+    fn add_to_key(&mut self, bytes: &[u8]) {
+        self.key.write(&(bytes.len() as u64).to_le_bytes());
+        self.key.write(bytes);
+    }
+
+    fn emit(
+        &mut self,
+        piece: FullPiece,
+        kind: EventKind,
+        square: Square,
+        capturer: Option<FullPiece>,
+    ) {
+        self.events.push(PieceEvent {
+            piece,
+            kind,
+            square,
+            moves: self.moves,
+            ply: self.ply,
+            capturer,
+        });
+    }
+
+    // Replace the current position and rebuild the piece map from its board.
+    // In the standard and Chess960 start positions every piece is identified
+    // by its home file. Any other position may have two pieces of a kind on
+    // one file, so there each piece is identified by its starting square.
+    fn set_position(&mut self, pos: Chess) {
+        self.pieces.clear();
+
+        let home_files = is_start_position(pos.board());
+        for (square, piece) in pos.board().pieces() {
+            self.pieces.insert(
+                square,
+                FullPiece {
+                    color: piece.color,
+                    role: piece.role,
+                    starting_file: Some(square.file()).filter(|_| home_files),
+                    starting_square: Some(square).filter(|_| !home_files),
+                },
+            );
+        }
+
+        self.pos = pos;
+    }
+
+    // Record the first thing that went wrong in this game. Everything after
+    // it is ignored, and the game is rejected when it ends.
+    fn fail(&mut self, error: GameError) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+
+    // Take the piece on `square` off the board, checking it is what shakmaty
+    // says it should be.
+    fn take_piece(&mut self, square: Square, role: Role) -> Result<FullPiece, GameError> {
+        match self.pieces.remove(&square) {
+            Some(piece) if piece.role == role => Ok(piece),
+            Some(piece) => Err(GameError::PieceMapDesync(format!(
+                "expected {:?} on {}, found {}",
+                role, square, piece
+            ))),
+            None => Err(GameError::PieceMapDesync(format!(
+                "expected {:?} on {}, found nothing",
+                role, square
+            ))),
+        }
+    }
+
+    fn ensure_empty(&self, square: Square) -> Result<(), GameError> {
+        match self.pieces.get(&square) {
+            Some(piece) => Err(GameError::PieceMapDesync(format!(
+                "expected {} to be empty, found {}",
+                square, piece
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn play(&mut self, san_plus: &SanPlus) -> Result<(), GameError> {
+        let m = san_plus
+            .san
+            .to_move(&self.pos)
+            .map_err(|_| GameError::IllegalMove(san_plus.to_string()))?;
+
+        let fullmoves = u32::from(self.pos.fullmoves()) as usize;
+        self.moves = fullmoves;
+        self.ply = 2 * (fullmoves - 1) + self.pos.turn().fold(1, 2);
+
+        match &m {
+            Move::Normal {
+                role,
+                from,
+                capture,
+                to,
+                promotion,
+            } => {
+                let from_piece = self.take_piece(*from, *role)?;
+
+                if let Some(capture) = capture {
+                    let to_piece = self.take_piece(*to, *capture)?;
+                    if from_piece.color == to_piece.color {
+                        return Err(GameError::PieceMapDesync(format!(
+                            "{} captures its own {} on {}",
+                            from_piece, to_piece, to
+                        )));
+                    }
+                    self.emit(to_piece, EventKind::Captured, *to, Some(from_piece));
+                } else {
+                    self.ensure_empty(*to)?;
+                }
+
+                if let Some(promotion) = promotion {
+                    self.emit(from_piece, EventKind::Promoted, *to, None);
+                    let new_piece = FullPiece {
+                        color: from_piece.color,
+                        role: *promotion,
+                        starting_file: None,
+                        starting_square: None,
+                    };
+
+                    if self.trajectories {
+                        self.emit(new_piece, EventKind::Started, *to, None);
+                    }
+                    self.pieces.insert(*to, new_piece);
+                } else {
+                    if self.trajectories {
+                        self.emit(from_piece, EventKind::Moved, *to, None);
+                    }
+                    self.pieces.insert(*to, from_piece);
+                }
+            }
+            Move::Castle { king, rook } => {
+                // In Chess960 the king or rook may already stand on its
+                // destination, so take both off the board before placing them.
+                let side = CastlingSide::from_queen_side(rook < king);
+                let rook_piece = self.take_piece(*rook, Role::Rook)?;
+                let king_piece = self.take_piece(*king, Role::King)?;
+                if king_piece.color != rook_piece.color {
+                    return Err(GameError::PieceMapDesync(format!(
+                        "{} castles with {}",
+                        king_piece, rook_piece
+                    )));
+                }
+
+                let rook_square = side.rook_to(rook_piece.color);
+                let king_square = side.king_to(king_piece.color);
+                self.ensure_empty(king_square)?;
+                self.ensure_empty(rook_square)?;
+
+                self.pieces.insert(rook_square, rook_piece);
+                self.pieces.insert(king_square, king_piece);
+                self.emit(king_piece, EventKind::Castled(side), king_square, None);
+                if self.trajectories {
+                    self.emit(king_piece, EventKind::Moved, king_square, None);
+                    self.emit(rook_piece, EventKind::Moved, rook_square, None);
+                }
+            }
+            Move::EnPassant { from, to } => {
+                let captured_square = Square::from_coords(to.file(), from.rank());
+                let captured_pawn = self.take_piece(captured_square, Role::Pawn)?;
+                let pawn = self.take_piece(*from, Role::Pawn)?;
+                if pawn.color == captured_pawn.color {
+                    return Err(GameError::PieceMapDesync(format!(
+                        "{} captures its own {} en passant",
+                        pawn, captured_pawn
+                    )));
+                }
+                self.ensure_empty(*to)?;
+                self.emit(
+                    captured_pawn,
+                    EventKind::Captured,
+                    captured_square,
+                    Some(pawn),
+                );
+                if self.trajectories {
+                    self.emit(pawn, EventKind::Moved, *to, None);
+                }
+                self.pieces.insert(*to, pawn);
+            }
+            Move::Put { .. } => {
+                return Err(GameError::UnsupportedVariant("piece drops".to_string()));
+            }
+        }
+
+        self.pos.play_unchecked(&m);
+
+        // Credit checks to the pieces giving them, rather than trusting the
+        // SAN suffix, so discovered and double checks are attributed too.
+        let checkers = self.pos.checkers();
+        if !checkers.is_empty() {
+            let kind = if self.pos.is_checkmate() {
+                EventKind::DeliveredMate
+            } else {
+                EventKind::GaveCheck
+            };
+
+            for square in checkers {
+                let checker = *self.pieces.get(&square).ok_or_else(|| {
+                    GameError::PieceMapDesync(format!("expected checking piece on {}", square))
+                })?;
+                self.emit(checker, kind, square, None);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Whether `board` is a standard or Chess960 start position: each side's eight
+// pawns on its second rank and eight other pieces on its first.
+fn is_start_position(board: &Board) -> bool {
+    let on_home_rank = |(square, piece): (Square, Piece)| {
+        let rank = if piece.role == Role::Pawn {
+            piece.color.fold(Rank::Second, Rank::Seventh)
+        } else {
+            piece.color.fold(Rank::First, Rank::Eighth)
+        };
+        square.rank() == rank
+    };
+
+    board.pieces().count() == 32 && board.pieces().all(on_home_rank)
+}
+
+impl Visitor for LastPosition {
+    type Result = Game;
+
+    fn begin_game(&mut self) {
+        self.moves = 0;
+        self.ply = 0;
+        self.error = None;
+        self.setup = false;
+        self.chess960 = false;
+        self.events.clear();
+        self.key = SipHasher13::new();
+        for value in self.header_values.iter_mut() {
+            *value = None;
+        }
+        self.set_position(Chess::default());
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        if SEVEN_TAG_ROSTER.contains(&key) {
+            self.add_to_key(key);
+            self.add_to_key(value.as_bytes());
+        }
+
+        for (name, slot) in self.header_names.iter().zip(self.header_values.iter_mut()) {
+            if name.as_bytes() == key {
+                *slot = Some(value.decode_utf8_lossy().into_owned());
+            }
+        }
+
+        // Support games from a non-standard starting position.
+        if key == b"FEN" {
+            let pos = Fen::from_ascii(value.as_bytes())
+                .ok()
+                .and_then(|f| f.position().ok());
+
+            match pos {
+                Some(pos) => {
+                    self.setup = true;
+                    self.set_position(pos);
+                }
+                None => {
+                    let fen = value.decode_utf8_lossy().into_owned();
+                    self.fail(GameError::BadFen(fen));
+                }
+            }
+        }
+
+        // Chess960 castling is handled by shakmaty, so we only need to know
+        // which variants we can replay.
+        if key == b"Variant" {
+            let variant = value.decode_utf8_lossy();
+            match variant.to_ascii_lowercase().as_str() {
+                "standard" | "from position" => (),
+                "chess960" | "chess 960" | "fischerandom" | "fischer random" => {
+                    self.chess960 = true;
+                }
+                _ => self.fail(GameError::UnsupportedVariant(variant.into_owned())),
+            }
+        }
+    }
+
+    fn end_headers(&mut self) -> Skip {
+        if self.chess960 && !self.setup {
+            self.fail(GameError::MissingFen("Chess960".to_string()));
+        }
+
+        let skip = self.error.is_some();
+
+        if self.trajectories && !skip {
+            let pieces: Vec<_> = self.pieces.iter().map(|(k, v)| (*k, *v)).collect();
+            for (square, piece) in pieces {
+                self.emit(piece, EventKind::Started, square, None);
+            }
+        }
+
+        Skip(skip)
+    }
+
+    fn begin_variation(&mut self) -> Skip {
+        Skip(true) // stay in the mainline
+    }
+
+    fn san(&mut self, san_plus: SanPlus) {
+        self.add_to_key(san_plus.to_string().as_bytes());
+
+        if self.error.is_some() {
+            return;
+        }
+
+        if let Err(error) = self.play(&san_plus) {
+            self.fail(error);
+        }
+    }
+
+    fn outcome(&mut self, outcome: Option<Outcome>) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let winner = match outcome {
+            Some(o) => {
+                if let Outcome::Decisive { winner } = o {
+                    Some(winner)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+        let pieces: Vec<_> = self.pieces.iter().map(|(k, v)| (*k, *v)).collect();
+        for (key, val) in pieces {
+            if val.role == Role::King && winner.is_some() && val.color != winner.unwrap() {
+                self.emit(val, EventKind::Captured, key, None);
+            } else {
+                self.emit(val, EventKind::Survived, key, None);
+            }
+        }
+    }
+
+    fn end_game(&mut self) -> Self::Result {
+        let empty = vec![None; self.header_names.len()];
+        Game {
+            key: self.key.finish(),
+            headers: mem::replace(&mut self.header_values, empty),
+            events: mem::take(&mut self.events),
+            error: self.error.take(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    fn replay(pgn: &str, trajectories: bool) -> Vec<Game> {
+        crate::games(pgn.as_bytes(), Vec::new(), trajectories)
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    fn rows(game: &Game, kind: EventKind) -> Vec<Vec<String>> {
+        game.events
+            .iter()
+            .filter(|event| event.kind == kind)
+            .map(|event| {
+                // Leave out the game's key, which is checked on its own.
+                let mut row = event.columns(game);
+                row.remove(GAME_KEY_COLUMN);
+                row
+            })
+            .collect()
+    }
+
+    #[test]
+    fn numbering_restarts_with_each_game() {
+        let games = replay(
+            "[Event \"first\"]\n\n1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 *\n\n\
+             [Event \"second\"]\n\n1. d4 e5 2. dxe5 *\n",
+            false,
+        );
+        assert_eq!(games.len(), 2);
+        assert!(games.iter().all(|game| game.error.is_none()));
+
+        assert_eq!(
+            rows(&games[1], EventKind::Captured),
+            vec![vec![
+                "Black-Pawn-E",
+                "captured",
+                "e5",
+                "2",
+                "White-Pawn-D",
+                "3"
+            ]]
+        );
+        // Pieces survive as of the last move played.
+        let survived = rows(&games[1], EventKind::Survived);
+        assert_eq!(survived.len(), 31);
+        assert!(survived.iter().all(|row| row[3] == "2" && row[5] == "3"));
+    }
+
+    #[test]
+    fn game_keys_depend_only_on_the_game() {
+        let game = "[Event \"a\"]\n[White \"x\"]\n\n1. e4 e5 *\n";
+        let other = "[Event \"a\"]\n[White \"x\"]\n\n1. e4 c5 *\n";
+        let alone = replay(game, false);
+        let together = replay(&format!("{}\n{}", other, game), false);
+        assert_eq!(alone[0].key, together[1].key);
+        assert_ne!(together[0].key, together[1].key);
+
+        // Tags outside the Seven Tag Roster don't change the key.
+        let annotated = replay(
+            "[Event \"a\"]\n[White \"x\"]\n[Annotator \"y\"]\n\n1. e4 e5 *\n",
+            false,
+        );
+        assert_eq!(annotated[0].key, alone[0].key);
+    }
+
+    #[test]
+    fn numbering_starts_from_the_fen() {
+        let games = replay(
+            "[FEN \"4k3/8/8/8/8/8/4P3/4K3 b - - 0 30\"]\n[SetUp \"1\"]\n\n\
+             30... Kd7 31. e4 *\n",
+            true,
+        );
+        assert_eq!(games.len(), 1);
+        assert!(games[0].error.is_none());
+
+        assert_eq!(
+            rows(&games[0], EventKind::Moved),
+            vec![
+                vec!["Black-King-E8", "moved", "d7", "30", "null", "60"],
+                vec!["White-Pawn-E2", "moved", "e4", "31", "null", "61"],
+            ]
+        );
+    }
+
+    #[test]
+    fn set_up_pieces_are_told_apart_by_square() {
+        // Both white rooks start on the a-file.
+        let games = replay(
+            "[FEN \"r3k3/8/8/8/8/R7/8/R3K3 b - - 0 1\"]\n[SetUp \"1\"]\n\n\
+             1... Rxa3 2. Rxa3 *\n",
+            false,
+        );
+        assert!(games[0].error.is_none());
+
+        assert_eq!(
+            rows(&games[0], EventKind::Captured),
+            vec![
+                vec!["White-Rook-A3", "captured", "a3", "1", "Black-Rook-A8", "2"],
+                vec!["Black-Rook-A8", "captured", "a3", "2", "White-Rook-A1", "3"],
+            ]
+        );
+    }
+
+    #[test]
+    fn chess960_castling_may_leave_the_king_in_place() {
+        // The king starts on g1, so castling kingside only moves the rook
+        // from h1 to f1, once the other rook has left f1.
+        let games = replay(
+            "[Variant \"Chess960\"]\n\
+             [FEN \"bbqnnrkr/pppppppp/8/8/8/8/PPPPPPPP/BBQNNRKR w KQkq - 0 1\"]\n\n\
+             1. f4 f5 2. Rf3 Rf6 3. O-O O-O *\n",
+            false,
+        );
+        assert!(games[0].error.is_none(), "{:?}", games[0].error);
+
+        assert_eq!(
+            rows(&games[0], EventKind::Castled(CastlingSide::KingSide)),
+            vec![
+                vec!["White-King-G", "castled_kingside", "g1", "3", "null", "5"],
+                vec!["Black-King-G", "castled_kingside", "g8", "3", "null", "6"],
+            ]
+        );
+        let survived = rows(&games[0], EventKind::Survived);
+        for (piece, square) in [
+            ("White-King-G", "g1"),
+            ("White-Rook-H", "f1"),
+            ("White-Rook-F", "f3"),
+            ("Black-Rook-H", "f8"),
+        ] {
+            assert!(
+                survived
+                    .iter()
+                    .any(|row| row[0] == piece && row[2] == square),
+                "{} should survive on {}",
+                piece,
+                square
+            );
+        }
+    }
+
+    #[test]
+    fn variants_are_checked_before_replaying() {
+        let games = replay(
+            "[Variant \"Chess960\"]\n\n1. e4 *\n\n\
+             [Variant \"Atomic\"]\n\n1. e4 *\n\n\
+             [Variant \"Standard\"]\n\n1. e4 *\n",
+            false,
+        );
+        assert_eq!(games.len(), 3);
+        assert_eq!(
+            games[0].error,
+            Some(GameError::MissingFen("Chess960".to_string()))
+        );
+        assert_eq!(
+            games[1].error,
+            Some(GameError::UnsupportedVariant("Atomic".to_string()))
+        );
+        assert!(games[0].events.is_empty() && games[1].events.is_empty());
+        assert!(games[2].error.is_none());
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use chess_diff::diff::{rule, DiffConfig};
+use chess_diff::event_rows;
+use chess_diff::metric::{CountBounds, RuleCounts};
+use chess_diff::sketch::CohortSketches;
+
+// Captures and survivals of pieces on e4 and e5 from a few hundred games,
+// where black pieces are taken on e5 by knights far more often than on e4.
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/events.csv");
+
+fn config() -> DiffConfig {
+    DiffConfig::from_args(vec![FIXTURE.to_string()]).unwrap()
+}
+
+fn rows(config: &DiffConfig) -> Vec<Vec<String>> {
+    event_rows(FIXTURE, &config.header_names)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+// Every rule a row matches, with "*" for the attributes it doesn't specify,
+// as the diff examples build them.
+fn rules<'a>(config: &'a DiffConfig, row: &[String]) -> impl Iterator<Item = Vec<String>> + 'a {
+    let attributes = config.attributes_of(row);
+    config.combinations().into_iter().map(move |combination| {
+        rule(&attributes, &combination)
+            .into_iter()
+            .map(|a| a.unwrap_or_else(|| "*".to_string()))
+            .collect()
+    })
+}
+
+// The counts of every rule seen in the test cohort, as basic-diff counts
+// them.
+fn exact_counts(config: &DiffConfig) -> HashMap<Vec<String>, RuleCounts> {
+    let mut first: HashMap<Vec<String>, u64> = HashMap::new();
+    let mut second: HashMap<Vec<String>, u64> = HashMap::new();
+    let (mut first_total, mut second_total) = (0, 0);
+    for row in rows(config) {
+        if config.cohorts.test.matches(&row) {
+            for rule in rules(config, &row) {
+                *first.entry(rule).or_default() += 1;
+            }
+            first_total += 1;
+        }
+        if config.cohorts.control.matches(&row) {
+            for rule in rules(config, &row) {
+                *second.entry(rule).or_default() += 1;
+            }
+            second_total += 1;
+        }
+    }
+
+    first
+        .into_iter()
+        .map(|(rule, count)| {
+            let counts = RuleCounts {
+                first_rule: count,
+                first_total,
+                second_rule: second.get(&rule).copied().unwrap_or(0),
+                second_total,
+            };
+            (rule, counts)
+        })
+        .collect()
+}
+
+// The bounds space-saving reports for every rule its sketches track, with
+// `limit` counters per cohort.
+fn sketched_bounds(config: &DiffConfig, limit: usize) -> Vec<(Vec<String>, CountBounds)> {
+    let mut sketches = CohortSketches::new(limit);
+    for row in rows(config) {
+        if config.cohorts.test.matches(&row) {
+            for rule in rules(config, &row) {
+                sketches.insert_first(rule, None);
+            }
+            sketches.first_total += 1;
+        }
+        if config.cohorts.control.matches(&row) {
+            for rule in rules(config, &row) {
+                sketches.insert_second(rule);
+            }
+            sketches.second_total += 1;
+        }
+    }
+
+    sketches
+        .rule_bounds()
+        .into_iter()
+        .map(|(rule, bounds, _)| (rule, bounds))
+        .collect()
+}
+
+fn reported(config: &DiffConfig, counts: &RuleCounts) -> bool {
+    counts.first_support() >= config.min_support && config.metric.compute(counts) > config.threshold
+}
+
+// The rules each diff reports, with their scores. space-saving scores rules
+// on their least favourable counts.
+fn exact_diff(config: &DiffConfig) -> HashMap<Vec<String>, f64> {
+    exact_counts(config)
+        .into_iter()
+        .filter(|(_, counts)| reported(config, counts))
+        .map(|(rule, counts)| (rule, config.metric.compute(&counts)))
+        .collect()
+}
+
+fn approximate_diff(config: &DiffConfig, limit: usize) -> HashMap<Vec<String>, f64> {
+    sketched_bounds(config, limit)
+        .into_iter()
+        .map(|(rule, bounds)| (rule, bounds.worst()))
+        .filter(|(_, counts)| reported(config, counts))
+        .map(|(rule, counts)| (rule, config.metric.compute(&counts)))
+        .collect()
+}
+
+#[test]
+fn exact_counts_lie_within_the_sketch_bounds() {
+    let config = config();
+    let exact = exact_counts(&config);
+    for limit in [20, 100, 1000] {
+        for (rule, bounds) in sketched_bounds(&config, limit) {
+            let counts = exact[&rule];
+            assert!(
+                (bounds.lower.first_rule..=bounds.upper.first_rule).contains(&counts.first_rule),
+                "{:?}: test cohort count {} outside {:?}",
+                rule,
+                counts.first_rule,
+                bounds
+            );
+            assert!(
+                (bounds.lower.second_rule..=bounds.upper.second_rule).contains(&counts.second_rule),
+                "{:?}: control cohort count {} outside {:?}",
+                rule,
+                counts.second_rule,
+                bounds
+            );
+        }
+    }
+}
+
+#[test]
+fn approximate_diff_is_conservative() {
+    let config = config();
+    let exact = exact_diff(&config);
+    assert!(!exact.is_empty());
+
+    // With few enough counters that many rules are evicted from both cohorts'
+    // sketches, every rule reported is one the exact diff reports, with a
+    // ratio no higher.
+    for limit in [20, 100, 1000] {
+        let approximate = approximate_diff(&config, limit);
+        for (rule, ratio) in approximate.iter() {
+            let exact_ratio = exact
+                .get(rule)
+                .unwrap_or_else(|| panic!("{:?} reported with {} counters only", rule, limit));
+            assert!(
+                ratio <= exact_ratio,
+                "{:?}: {} > {}",
+                rule,
+                ratio,
+                exact_ratio
+            );
+        }
+    }
+    assert!(!approximate_diff(&config, 100).is_empty());
+}
+
+#[test]
+fn approximate_diff_is_exact_without_evictions() {
+    let config = config();
+    assert_eq!(approximate_diff(&config, 100_000), exact_diff(&config));
+}